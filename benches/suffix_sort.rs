@@ -0,0 +1,47 @@
+//! Compares the serial and parallel suffix sorts on typical and repetitive
+//! inputs.  Run with `cargo bench --bench suffix_sort`.
+
+extern crate rsdiff;
+
+use std::time::{Duration, Instant};
+
+use rsdiff::diff::Index;
+
+#[path = "../src/test_util/random.rs"]
+mod random;
+
+use random::pseudo_random;
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn main() {
+    let len = 1 << 20;
+    let block = pseudo_random(4096, 1);
+
+    let inputs = vec![
+        ("zeros", vec![0u8; len]),
+        ("period 7", b"abcdefg".iter().cycle().take(len).cloned().collect()),
+        ("period 4096", block.iter().cycle().take(len).cloned().collect()),
+        ("random", pseudo_random(len, 2)),
+        ("random, repeated once", {
+            let mut data = pseudo_random(len / 2, 3);
+            let copy = data.clone();
+            data.extend_from_slice(&copy);
+            data
+        }),
+    ];
+
+    for (name, data) in inputs {
+        let serial = time(|| { Index::compute(data.clone()); });
+        println!("{}: serial {:?}", name, serial);
+
+        for &threads in &[2, 4, 8] {
+            let parallel = time(|| { Index::compute_parallel(data.clone(), threads); });
+            println!("{}: {} threads {:?}", name, threads, parallel);
+        }
+    }
+}
@@ -5,8 +5,9 @@ use std::fs::{self, File};
 use std::io::{self, Read, Write, Cursor};
 use std::{env, fmt};
 
-use rsdiff::diff::{Cache, Index};
+use rsdiff::diff::{Cache, Index, DiffOptions};
 use rsdiff::format::bsdiff::generate_full_patch;
+use rsdiff::hash_chain::HashChainIndex;
 
 fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     let mut contents = Vec::new();
@@ -56,23 +57,34 @@ impl<'a> Cache for &'a mut FileCache {
     fn get_writer(&self, digest: &[u8; 20]) -> io::Result<Self::Write> {
         File::create(self.path.join(format!("{}", Hex(digest))))
     }
+
+    fn get_file(&self, digest: &[u8; 20]) -> io::Result<Option<File>> {
+        self.get(digest)
+    }
 }
 
 fn main() {
     let args = env::args().collect::<Vec<_>>();
 
-    if args.len() != 4 {
-        panic!("Expected 3 arguments");
+    if args.len() != 4 && args.len() != 5 {
+        panic!("Expected 3 arguments, and optionally a hash chain level (1-9) for a quick diff");
     }
 
     let old = load(&args[1]).unwrap();
     let new = load(&args[2]).unwrap();
 
-    let mut cache = FileCache::new(PathBuf::from(".cache"));
+    let patch_data = if args.len() == 5 {
+        let level = args[4].parse().expect("Expected a level from 1 to 9");
+        let old_index = HashChainIndex::compute(old, level);
+
+        generate_full_patch(&old_index, &new, &DiffOptions::default())
+    } else {
+        let mut cache = FileCache::new(PathBuf::from(".cache"));
 
-    let old_index = Index::from_cache_or_compute(&mut cache, old).unwrap();
+        let old_index = Index::from_cache_or_compute(&mut cache, old).unwrap();
 
-    let patch_data = generate_full_patch(&old_index, &new);
+        generate_full_patch(&old_index, &new, &DiffOptions::default())
+    };
 
     File::create(&args[3]).unwrap().write_all(&patch_data).unwrap();
 }
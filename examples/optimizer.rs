@@ -5,7 +5,7 @@ use std::fs::{self, File};
 use std::io::{self, Read};
 use std::fmt;
 
-use rsdiff::diff::{Cache, Index, DiffStat};
+use rsdiff::diff::{Cache, Index, DiffStat, DiffOptions};
 
 fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     let mut contents = Vec::new();
@@ -55,6 +55,10 @@ impl<'a> Cache for &'a mut FileCache {
     fn get_writer(&self, digest: &[u8; 20]) -> io::Result<Self::Write> {
         File::create(self.path.join(format!("{}", Hex(digest))))
     }
+
+    fn get_file(&self, digest: &[u8; 20]) -> io::Result<Option<File>> {
+        self.get(digest)
+    }
 }
 
 fn main() {
@@ -65,7 +69,29 @@ fn main() {
 
     let index_a = Index::from_cache_or_compute(&mut cache, a).unwrap();
 
-    let stat = DiffStat::from(&index_a, &b);
+    let presets = [
+        ("fast", DiffOptions::fast()),
+        ("default", DiffOptions::default()),
+        ("max_compression", DiffOptions::max_compression()),
+    ];
+
+    for &(name, ref options) in presets.iter() {
+        let stat = DiffStat::from(&index_a, &b, options);
+
+        println!("{} {:?}: {:?}", name, options, stat);
+    }
 
-    println!("{:?}", stat);
+    for min_match_len in [6, 8, 12, 16, 32].iter() {
+        for mismatch_budget in [4, 8, 16].iter() {
+            let options = DiffOptions {
+                min_match_len: *min_match_len,
+                mismatch_budget: *mismatch_budget,
+                ..DiffOptions::default()
+            };
+
+            let stat = DiffStat::from(&index_a, &b, &options);
+
+            println!("{:?}: {:?}", options, stat);
+        }
+    }
 }
\ No newline at end of file
@@ -1,67 +1,227 @@
-use std::io::{self, Read, Write, BufReader};
+use std::io::{self, Write};
+use std::fmt;
+use std::fs::File;
 use std::cmp::{min, max, Ordering};
 use std::ops::Range;
+use std::{u32, usize};
 use std::{mem, str};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::time::{Duration, Instant};
 
-use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
 use bzip2;
 use sha1::Sha1;
 
+use format::index;
+use matcher::entropy::EntropyModel;
+use suffix_array::{self, Offsets, LcpTable};
+
 pub trait Cache {
     type Read: io::Read;
     type Write: io::Write;
 
     fn get(&self, digest: &[u8; 20]) -> io::Result<Option<Self::Read>>;
     fn get_writer(&self, digest: &[u8; 20]) -> io::Result<Self::Write>;
+
+    /// Returns the cache entry as a file that can be memory-mapped, if the
+    /// cache is backed by the filesystem.  When this returns a file, the index
+    /// is used in place instead of being read through `get`.
+    fn get_file(&self, _digest: &[u8; 20]) -> io::Result<Option<File>> {
+        Ok(None)
+    }
+
+    /// The zstd level to compress new entries with, or `None` to store them
+    /// uncompressed.  Compressed entries are much smaller, but can't be
+    /// memory-mapped.
+    fn compression_level(&self) -> Option<i32> {
+        None
+    }
+
+    /// Whether to fully check entries from `get_file` when loading them, so
+    /// that a corrupt one is recomputed.  A cache whose files can't be
+    /// corrupted in place may return false to skip reading the whole table,
+    /// but a bad offset in an unchecked entry makes matching panic.  Entries
+    /// read through `get` are always fully checked.
+    fn verify_on_load(&self) -> bool {
+        true
+    }
+}
+
+/// Something `MatchIter` can find matches in.
+pub trait MatchIndex {
+    /// The data that found matches point into.
+    fn data(&self) -> &[u8];
+
+    /// Returns the range of `data()` that matches the longest possible prefix
+    /// of `buf`.
+    fn longest_match(&self, buf: &[u8]) -> Range<usize>;
+
+    /// Like `longest_match`, but when several places in `data()` match
+    /// equally long, prefers the one starting closest to `hint`.
+    fn longest_match_near(&self, buf: &[u8], _hint: usize) -> Range<usize> {
+        self.longest_match(buf)
+    }
+
+    /// Returns which reference the byte at `offset` belongs to, and the range
+    /// of `data()` that reference covers.  Matches are never extended past
+    /// that range.
+    fn reference_bounds(&self, _offset: usize) -> (usize, Range<usize>) {
+        (0, 0 .. self.data().len())
+    }
 }
 
-const VERSION: u8 = 5;
+/// A way of choosing the matches a patch is built from.
+///
+/// The patch generators and `DiffStat` accept any strategy.  Every index is a
+/// strategy that uses `MatchIter`.
+pub trait MatchStrategy {
+    /// The data that matches point into.
+    fn old(&self) -> &[u8];
+
+    /// Returns matches covering all of `new`, in order: each match's delta
+    /// covers the bytes of `new` following the previous match's unmatched
+    /// suffix.  Strategies use whichever of `options` apply to them.
+    fn matches<'a>(&'a self, new: &'a [u8], options: &DiffOptions) -> Box<dyn Iterator<Item=Match> + 'a>;
+
+    /// Returns the range of `old()` covered by the reference containing the
+    /// byte at `offset`.  Passes that adjust matches never extend them past
+    /// that range.
+    fn reference_range(&self, _offset: usize) -> Range<usize> {
+        0 .. self.old().len()
+    }
+}
+
+impl<I: MatchIndex> MatchStrategy for I {
+    fn old(&self) -> &[u8] {
+        self.data()
+    }
+
+    fn matches<'a>(&'a self, new: &'a [u8], options: &DiffOptions) -> Box<dyn Iterator<Item=Match> + 'a> {
+        Box::new(MatchIter::with_options(self, new, options))
+    }
+
+    fn reference_range(&self, offset: usize) -> Range<usize> {
+        self.reference_bounds(offset).1
+    }
+}
+
+const VERSION: u8 = 7;
+
+/// `longest_match_near` looks at no more than this many equally long matches.
+const MAX_NEAR_CANDIDATES: usize = 256;
 
 pub struct Index {
     pub data: Vec<u8>,
-    offsets: Vec<usize>,
+    offsets: Offsets,
+    lcp: Option<LcpTable>,
+    references: Vec<Reference>,
+}
+
+/// One of the blobs an `Index` was built over, and where it lives in
+/// `Index::data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub name: String,
+    pub range: Range<usize>,
+}
+
+fn digest(data: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(&[VERSION]);
+    sha1.update(data);
+    sha1.digest().bytes()
+}
+
+/// Loads a cached index, if there is one.  An entry that fails validation is
+/// treated as missing, so the caller rebuilds it and overwrites it.
+fn load_cached<C: Cache>(cache: &C, digest: &[u8; 20], data_len: usize) -> io::Result<Option<Offsets>> {
+    let res = if let Some(file) = cache.get_file(digest)? {
+        println!("Mapping");
+        index::map_offsets(&file, digest, data_len, cache.verify_on_load())
+    } else if let Some(r) = cache.get(digest)? {
+        println!("Reading");
+        index::read_offsets(r, digest, data_len)
+    } else {
+        return Ok(None);
+    };
+
+    match res {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof || e.kind() == io::ErrorKind::InvalidData => {
+            println!("Discarding corrupt cache entry: {}", e);
+            Ok(None)
+        }
+        res => res,
+    }
+}
+
+fn store<C: Cache>(cache: &C, digest: &[u8; 20], offsets: &Offsets) -> io::Result<()> {
+    println!("Writing");
+
+    let w = cache.get_writer(digest)?;
+
+    match cache.compression_level() {
+        Some(level) => index::write_compressed_offsets(offsets, digest, level, w),
+        None => index::write_offsets(offsets, digest, w),
+    }
 }
 
 impl Index {
     pub fn from_cache_or_compute<C: Cache>(cache: C, data: Vec<u8>) -> io::Result<Index> {
         println!("Hashing");
 
-        let mut sha1 = Sha1::new();
-        sha1.update(&[VERSION]);
-        sha1.update(&data);
-        let digest = sha1.digest();
-            
+        let digest = digest(&data);
 
-        if let Some(mut r) = cache.get(&digest.bytes())? {
-            let mut offsets = Vec::new();
+        if let Some(offsets) = load_cached(&cache, &digest, data.len())? {
+            println!("Done");
 
-            let mut file_hash = [0u8; 20];
-            r.read_exact(&mut file_hash)?;
+            return Ok(Index::from_parts(data, offsets));
+        }
 
-            if file_hash == digest.bytes() {
-                println!("Reading");
+        let res = Index::compute(data);
 
-                // let mut r = BzDecoder::new(r);
-                let mut r = BufReader::new(r);
+        store(&cache, &digest, &res.offsets)?;
 
-                for _ in 0..data.len() as usize {
-                    offsets.push(r.read_u64::<LittleEndian>()? as usize);
-                }
+        println!("Done");
+
+        Ok(res)
+    }
 
-                println!("Done");
+    /// Like `from_cache_or_compute`, for data that's known to be an earlier
+    /// version (the first `parent_len` bytes) with more bytes appended.
+    ///
+    /// On a cache miss, if the earlier version's index is cached, the new
+    /// index is derived from it with `append` instead of being computed from
+    /// scratch.  A `parent_len` past the end of `data` is an `InvalidInput`
+    /// error.
+    pub fn from_cache_or_append<C: Cache>(cache: C, data: Vec<u8>, parent_len: usize) -> io::Result<Index> {
+        if parent_len > data.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Parent is longer than the data"));
+        }
 
-                return Ok(Index {
-                    data: data,
-                    offsets: offsets,
-                })
-            }
+        println!("Hashing");
+
+        let digest = digest(&data);
+
+        if let Some(offsets) = load_cached(&cache, &digest, data.len())? {
+            println!("Done");
+
+            return Ok(Index::from_parts(data, offsets));
         }
 
-        let res = Index::compute(data);
+        let parent_digest = self::digest(&data[..parent_len]);
 
-        println!("Writing");
+        let res = match load_cached(&cache, &parent_digest, parent_len)? {
+            Some(offsets) => {
+                let suffix = data[parent_len..].to_vec();
+                let mut parent = data;
+                parent.truncate(parent_len);
 
-        res.serialize_to(&digest.bytes(), cache.get_writer(&digest.bytes())?)?;
+                Index::from_parts(parent, offsets).append(&suffix)
+            }
+            None => Index::compute(data),
+        };
+
+        store(&cache, &digest, &res.offsets)?;
 
         println!("Done");
 
@@ -69,58 +229,197 @@ impl Index {
     }
 
     pub fn compute(data: Vec<u8>) -> Index {
-        println!("Initializing");
-        let mut offsets = Vec::new();
+        println!("Sorting");
+
+        let offsets = suffix_array::build(&data);
+
+        Index::from_parts(data, offsets)
+    }
 
-        for i in 0..data.len() as usize {
-            offsets.push(i);
+    /// Builds a single index over several named reference blobs.
+    ///
+    /// The blobs are laid out back to back in `data`, in the order given, but
+    /// matches never span two of them.  Each `Delta` produced from this index
+    /// records which reference it came from.
+    pub fn compute_multi(references: Vec<(String, Vec<u8>)>) -> Index {
+        let mut data = Vec::with_capacity(references.iter().map(|r| r.1.len()).sum());
+        let mut ranges = Vec::with_capacity(references.len());
+
+        for (name, blob) in references {
+            let start = data.len();
+            data.extend_from_slice(&blob);
+            ranges.push(Reference {
+                name: name,
+                range: start .. data.len(),
+            });
+        }
+
+        let mut index = Index::compute(data);
+        if !ranges.is_empty() {
+            index.references = ranges;
         }
+        index
+    }
 
+    /// Like `compute`, but spreads the suffix sort over up to `threads` threads.
+    ///
+    /// The resulting index is identical to the one `compute` produces.
+    pub fn compute_parallel(data: Vec<u8>, threads: usize) -> Index {
         println!("Sorting");
 
-        offsets.sort_by(|a, b| {
-            let sa = &data[*a as usize..];
-            let sb = &data[*b as usize..];
+        let offsets = suffix_array::build_parallel(&data, threads);
 
-            let mut i = 0;
-            let l = min(sb.len(), sa.len());
-            while i < l {
-                if sa[i] != sb[i] {
-                    return if sa[i] < sb[i] {
-                        Ordering::Less
-                    } else {
-                        Ordering::Greater
-                    };
-                }
-                i += 1;
-            }
-            if sa.len() < sb.len() {
+        Index::from_parts(data, offsets)
+    }
+
+    /// Extends the index to cover `suffix` appended to the end of the data.
+    ///
+    /// This only sorts the newly added suffixes (plus any trailing part of the
+    /// old data that repeats elsewhere in it) and merges them in, rather than
+    /// sorting everything again.  Appended bytes become part of the last
+    /// reference.
+    pub fn append(self, suffix: &[u8]) -> Index {
+        println!("Sorting");
+
+        let Index { mut data, offsets, lcp, mut references } = self;
+
+        let parent_len = data.len();
+        data.extend_from_slice(suffix);
+
+        let offsets = suffix_array::extend(&data, &offsets, parent_len);
+
+        references.last_mut().unwrap().range.end = data.len();
+
+        let mut res = Index {
+            data: data,
+            offsets: offsets,
+            lcp: None,
+            references: references,
+        };
+
+        if lcp.is_some() {
+            res.build_lcp();
+        }
+
+        res
+    }
+
+    fn from_parts(data: Vec<u8>, offsets: Offsets) -> Index {
+        let whole = Reference {
+            name: String::new(),
+            range: 0 .. data.len(),
+        };
+
+        Index {
+            data: data,
+            offsets: offsets,
+            lcp: None,
+            references: vec![whole],
+        }
+    }
+
+    /// Returns the start of the suffix of `data` with the given rank in sorted
+    /// order.  Ranks run from 0 to `data.len() - 1`; the empty suffix isn't
+    /// stored.
+    pub fn suffix_at(&self, rank: usize) -> usize {
+        self.offsets.get(rank)
+    }
+
+    /// Returns the rank `buf` would have among the sorted suffixes: the
+    /// suffixes sharing the longest prefixes with `buf` are at ranks just
+    /// before and after it.
+    pub fn rank_of(&self, buf: &[u8]) -> usize {
+        match self.offsets.binary_search_by(|v| self.data[v..].cmp(buf)) {
+            Ok(rank) => rank,
+            Err(rank) => rank,
+        }
+    }
+
+    pub fn references(&self) -> &[Reference] {
+        &self.references
+    }
+
+    /// Returns the position in `references()` of the reference containing the
+    /// byte at `offset`.
+    pub fn reference_at(&self, offset: usize) -> usize {
+        if self.references.len() == 1 {
+            return 0;
+        }
+
+        self.references.binary_search_by(|r| {
+            if r.range.end <= offset {
                 Ordering::Less
-            } else if sa.len() > sb.len() {
+            } else if r.range.start > offset {
                 Ordering::Greater
             } else {
                 Ordering::Equal
             }
-        });
+        }).expect("offset out of range")
+    }
 
-        Index {
-            data: data,
-            offsets: offsets,
+    /// Builds the LCP table that lets `longest_match` skip over prefixes it
+    /// has already compared.  This costs 12 extra bytes per byte of data.
+    pub fn build_lcp(&mut self) {
+        println!("Computing LCP");
+
+        self.lcp = Some(LcpTable::build(&self.data, &self.offsets));
+    }
+
+    /// Returns the first rank for which `pred` is false, given that it's true
+    /// for every rank before that and false after.
+    fn partition_point<F: FnMut(usize) -> bool>(&self, mut pred: F) -> usize {
+        let (mut lo, mut hi) = (0, self.offsets.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if pred(self.offsets.get(mid)) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
         }
+        lo
     }
 
-    fn serialize_to<W: Write>(&self, digest: &[u8; 20], mut w: W) -> io::Result<()> {
-        w.write_all(digest)?;
+    /// Like `longest_match`, but of all the places that match as long, picks
+    /// the one closest to `hint`.  Those places are the suffixes sharing the
+    /// match as a prefix, which are next to each other in the suffix array;
+    /// when there are more than `MAX_NEAR_CANDIDATES` of them, only the first
+    /// that many are considered.
+    pub fn longest_match_near(&self, buf: &[u8], hint: usize) -> Range<usize> {
+        let best = self.longest_match(buf);
+        let len = best.len();
+        if len == 0 {
+            return best;
+        }
 
-        for offset in &self.offsets {
-            w.write_u64::<LittleEndian>(*offset as u64)?;
+        let prefix = &buf[..len];
+        let key = |start: usize| &self.data[start..min(self.data.len(), start + len)];
+
+        let lo = self.partition_point(|start| key(start) < prefix);
+        let hi = min(self.partition_point(|start| key(start) <= prefix), lo + MAX_NEAR_CANDIDATES);
+
+        let mut res = best.start;
+        for rank in lo..hi {
+            let start = self.offsets.get(rank);
+            let fits = start + len <= self.references[self.reference_at(start)].range.end;
+            if fits && distance(start, hint) < distance(res, hint) {
+                res = start;
+            }
         }
 
-        Ok(())
+        res .. res + len
     }
 
     fn longest_match(&self, buf: &[u8]) -> Range<usize> {
-        let res = self.offsets.binary_search_by(|&v| {
+        if self.references.len() > 1 {
+            return self.longest_match_within_references(buf);
+        }
+
+        if let Some(ref lcp) = self.lcp {
+            return self.longest_match_with_lcp(lcp, buf);
+        }
+
+        let res = self.offsets.binary_search_by(|v| {
             let mut i = 0;
             let v = &self.data[v..];
             // println!("looking for {:?} in {:?} ",
@@ -155,19 +454,19 @@ impl Index {
 
         let (start, len) = match res {
             Ok(index) => {
-                let start = self.offsets[index];
+                let start = self.offsets.get(index);
                 let len = longest_prefix(buf, &self.data[start..]);
                 (start, len)
             }
             Err(index) => {
                 let lower_start = if index > 0 {
-                    self.offsets[index - 1]
+                    self.offsets.get(index - 1)
                 } else {
                     self.data.len()
                 };
 
                 let upper_start = if index < self.offsets.len() {
-                    self.offsets[index]
+                    self.offsets.get(index)
                 } else {
                     self.data.len()
                 };
@@ -185,9 +484,181 @@ impl Index {
 
         start .. start + len
     }
+
+    /// Finds the longest match for `buf` that doesn't cross from one reference
+    /// into the next.
+    ///
+    /// The longest unbounded match sits next to where `buf` would be inserted,
+    /// but once it's cut short at a reference boundary, another suffix a bit
+    /// further away may win.  So scan outwards from the insertion point until
+    /// the unbounded match length drops to what we've already found.
+    ///
+    /// Moving outwards, the unbounded match length is the smaller of the
+    /// previous one and the LCP of the two neighbouring suffixes.  With an LCP
+    /// table that's all it takes, so runs of repetitive data cost no byte
+    /// comparisons; without one, comparisons stop at the previous length.
+    fn longest_match_within_references(&self, buf: &[u8]) -> Range<usize> {
+        let n = self.offsets.len();
+
+        let pivot = match self.offsets.binary_search_by(|v| self.data[v..].cmp(buf)) {
+            Ok(index) => index,
+            Err(index) => index,
+        };
+
+        let adjacent = self.lcp.as_ref().map(|table| &table.adjacent[..]);
+
+        let mut best = (self.data.len(), 0);
+
+        {
+            // `neighbour` is the next rank towards the pivot, if it's been
+            // considered, and `reach` its unbounded match length.
+            let mut consider = |rank: usize, neighbour: Option<usize>, reach: usize| {
+                let start = self.offsets.get(rank);
+                let len = match (adjacent, neighbour) {
+                    (Some(adjacent), Some(neighbour)) =>
+                        min(reach, adjacent[max(rank, neighbour)] as usize),
+                    _ => longest_prefix(&buf[..reach], &self.data[start..]),
+                };
+                if len <= best.1 {
+                    return None;
+                }
+
+                let end = self.references[self.reference_at(start)].range.end;
+                let bounded = min(len, end - start);
+                if bounded > best.1 {
+                    best = (start, bounded);
+                }
+                Some(len)
+            };
+
+            let mut reach = buf.len();
+            for rank in pivot..n {
+                match consider(rank, if rank > pivot { Some(rank - 1) } else { None }, reach) {
+                    Some(len) => reach = len,
+                    None => break,
+                }
+            }
+
+            let mut reach = buf.len();
+            for rank in (0..pivot).rev() {
+                match consider(rank, if rank + 1 < pivot { Some(rank + 1) } else { None }, reach) {
+                    Some(len) => reach = len,
+                    None => break,
+                }
+            }
+        }
+
+        best.0 .. best.0 + best.1
+    }
+
+    /// Same result as the plain binary search in `longest_match`, but using
+    /// Manber & Myers' search: each probe starts comparing after the prefix
+    /// that's already known to match, so the whole search costs
+    /// O(buf.len() + log n) byte comparisons instead of O(buf.len() * log n).
+    fn longest_match_with_lcp(&self, table: &LcpTable, buf: &[u8]) -> Range<usize> {
+        let n = self.offsets.len();
+
+        if n == 0 {
+            return self.data.len() .. self.data.len();
+        }
+
+        // Compares `buf` against the suffix at rank `i`, given that their first
+        // `known` bytes match.  Returns the full match length, and whether
+        // `buf` sorts at or before that suffix.
+        let compare = |i: usize, known: usize| {
+            let suffix = &self.data[self.offsets.get(i)..];
+            let len = known + longest_prefix(&buf[known..], &suffix[known..]);
+            let at_or_before = len == buf.len() || (len < suffix.len() && buf[len] < suffix[len]);
+            (len, at_or_before)
+        };
+
+        let (mut l, first_at_or_before) = compare(0, 0);
+        if first_at_or_before {
+            let start = self.offsets.get(0);
+            return start .. start + l;
+        }
+
+        let (mut r, last_at_or_before) = compare(n - 1, 0);
+        if !last_at_or_before {
+            let start = if r > 0 { self.offsets.get(n - 1) } else { self.data.len() };
+            return start .. start + r;
+        }
+
+        // Invariant: suffix `lo` < buf <= suffix `hi`, with `l` and `r` their
+        // match lengths against `buf`.
+        let (mut lo, mut hi) = (0, n - 1);
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+
+            // The known-LCP shortcuts only apply when the stored length isn't
+            // saturated; otherwise fall through to a direct comparison.
+            let (known, shared) = if l >= r {
+                (l, table.left[mid] as usize)
+            } else {
+                (r, table.right[mid] as usize)
+            };
+
+            let go_left = if shared == known || shared == u32::MAX as usize {
+                // Every suffix between `lo` and `hi` matches at least
+                // `min(l, r)` bytes of `buf`, and when `shared == known` it
+                // matches `known` bytes.
+                let (len, at_or_before) = compare(mid, if shared == known { known } else { min(l, r) });
+                if at_or_before {
+                    r = len;
+                } else {
+                    l = len;
+                }
+                at_or_before
+            } else if l >= r {
+                // `mid` shares more (or less) with `lo` than `buf` does, so it
+                // sorts on the same side of `buf` as `lo` (or the other side).
+                if shared < known {
+                    r = shared;
+                }
+                shared < known
+            } else {
+                if shared < known {
+                    l = shared;
+                }
+                shared > known
+            };
+
+            if go_left {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+
+        let start = if l > r { self.offsets.get(lo) } else { self.offsets.get(hi) };
+        start .. start + max(l, r)
+    }
+}
+
+impl MatchIndex for Index {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+        Index::longest_match(self, buf)
+    }
+
+    fn longest_match_near(&self, buf: &[u8], hint: usize) -> Range<usize> {
+        Index::longest_match_near(self, buf, hint)
+    }
+
+    fn reference_bounds(&self, offset: usize) -> (usize, Range<usize>) {
+        let reference = self.reference_at(offset);
+        (reference, self.references[reference].range.clone())
+    }
+}
+
+fn distance(a: usize, b: usize) -> usize {
+    if a > b { a - b } else { b - a }
 }
 
-fn longest_prefix(a: &[u8], b: &[u8]) -> usize {
+pub fn longest_prefix(a: &[u8], b: &[u8]) -> usize {
     let mut i = 0;
     let l = min(a.len(), b.len());
     while i < l {
@@ -199,6 +670,216 @@ fn longest_prefix(a: &[u8], b: &[u8]) -> usize {
     return i;
 }
 
+/// Limits how much searching `MatchIter` does.  Clones share the same count,
+/// so the caller can see whether, and where, an iterator ran out.
+#[derive(Clone)]
+pub struct SearchLimit {
+    state: Arc<LimitState>,
+}
+
+struct LimitState {
+    deadline: Option<Instant>,
+    max_searches: usize,
+    searches: AtomicUsize,
+
+    /// Where in the new data searching stopped, or `usize::MAX`.
+    stopped_at: AtomicUsize,
+}
+
+impl SearchLimit {
+    fn new(deadline: Option<Instant>, max_searches: usize) -> SearchLimit {
+        SearchLimit {
+            state: Arc::new(LimitState {
+                deadline: deadline,
+                max_searches: max_searches,
+                searches: AtomicUsize::new(0),
+                stopped_at: AtomicUsize::new(usize::MAX),
+            }),
+        }
+    }
+
+    /// Runs out once `time` has passed from now.
+    pub fn time(time: Duration) -> SearchLimit {
+        SearchLimit::new(Some(Instant::now() + time), usize::MAX)
+    }
+
+    /// Runs out after this many index searches.  Unlike a time limit, this
+    /// finds the same matches on every run.
+    pub fn searches(searches: usize) -> SearchLimit {
+        SearchLimit::new(None, searches)
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.state.searches.load(AtomicOrdering::Relaxed) >= self.state.max_searches ||
+            self.state.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Counts a search, and returns whether it's allowed.
+    fn spend(&self) -> bool {
+        let exceeded = self.exceeded();
+        self.state.searches.fetch_add(1, AtomicOrdering::Relaxed);
+        !exceeded
+    }
+
+    /// Records that searching stopped at `position`.  Of several iterators
+    /// sharing the limit, the earliest stop counts.
+    fn stop(&self, position: usize) {
+        self.state.stopped_at.fetch_min(position, AtomicOrdering::Relaxed);
+    }
+
+    /// If searching ran out, the position in the new data it stopped at,
+    /// counting from the start of the whole new data (see
+    /// `DiffOptions::new_offset`).  Everything from there on was left
+    /// unmatched without being searched.
+    pub fn stopped_at(&self) -> Option<usize> {
+        match self.state.stopped_at.load(AtomicOrdering::Relaxed) {
+            usize::MAX => None,
+            pos => Some(pos),
+        }
+    }
+}
+
+impl fmt::Debug for SearchLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SearchLimit")
+            .field("deadline", &self.state.deadline)
+            .field("max_searches", &self.state.max_searches)
+            .field("searches", &self.state.searches)
+            .finish()
+    }
+}
+
+impl PartialEq for SearchLimit {
+    fn eq(&self, other: &SearchLimit) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl Eq for SearchLimit {}
+
+/// Called with each decision `MatchIter` makes, to find out why a region of
+/// the new data wasn't matched.  Shared by clones, so it can be called from
+/// several threads at once, as under a `Chunked` strategy.  Positions are in
+/// the whole new data, even when it's matched in parts (see
+/// `DiffOptions::new_offset`).
+#[derive(Clone)]
+pub struct TraceHook(Arc<dyn Fn(&Decision) + Send + Sync>);
+
+impl TraceHook {
+    pub fn new<F: Fn(&Decision) + Send + Sync + 'static>(hook: F) -> TraceHook {
+        TraceHook(Arc::new(hook))
+    }
+}
+
+impl fmt::Debug for TraceHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TraceHook")
+    }
+}
+
+impl PartialEq for TraceHook {
+    fn eq(&self, other: &TraceHook) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for TraceHook {}
+
+/// Thresholds `MatchIter` uses to find and extend matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// Exact matches shorter than this are ignored.
+    pub min_match_len: usize,
+
+    /// After a search finds no match of `min_match_len` bytes, skip at least
+    /// this many bytes before searching again.  Above 1, this cuts the
+    /// searches in dissimilar regions by about this factor, but matches
+    /// shorter than `min_match_len + miss_skip - 1` bytes may be missed.
+    pub miss_skip: usize,
+
+    /// A partial match is extended until it has seen this many mismatched
+    /// bytes.
+    pub mismatch_budget: usize,
+
+    /// A partial match only ends where at least this percentage of its bytes
+    /// match.
+    pub similarity_percent: usize,
+
+    /// Of several equally long matches, take the one closest to where the
+    /// previous match ended in the old data.  This keeps bsdiff's seeks
+    /// small, which makes the command stream compress better.
+    pub prefer_nearby: bool,
+
+    /// Trim partial matches to where they're estimated to compress better
+    /// as delta than as extra data, using an `EntropyModel`.
+    pub cost_aware: bool,
+
+    /// Once this runs out, stop searching and leave the rest of the new data
+    /// unmatched.
+    pub limit: Option<SearchLimit>,
+
+    /// Where the new data being matched starts in the whole new data, for
+    /// strategies that match parts of it separately.  Positions reported to
+    /// `limit` and `trace` are offset by this.
+    pub new_offset: usize,
+
+    /// Reports every decision made while searching for matches.  Only
+    /// strategies built on `MatchIter` report anything.
+    pub trace: Option<TraceHook>,
+}
+
+impl DiffOptions {
+    /// Only takes long matches and extends them cautiously, which gives
+    /// fewer, larger commands, and only searches every 8th byte of unmatched
+    /// data.
+    pub fn fast() -> DiffOptions {
+        DiffOptions {
+            min_match_len: 16,
+            miss_skip: 8,
+            mismatch_budget: 4,
+            similarity_percent: 50,
+            prefer_nearby: false,
+            cost_aware: false,
+            limit: None,
+            new_offset: 0,
+            trace: None,
+        }
+    }
+
+    /// Takes short matches and extends them through more mismatches, which
+    /// usually gives the smallest patches for binaries at the cost of more
+    /// commands.
+    pub fn max_compression() -> DiffOptions {
+        DiffOptions {
+            min_match_len: 6,
+            miss_skip: 1,
+            mismatch_budget: 16,
+            similarity_percent: 40,
+            prefer_nearby: true,
+            cost_aware: true,
+            limit: None,
+            new_offset: 0,
+            trace: None,
+        }
+    }
+}
+
+impl Default for DiffOptions {
+    fn default() -> DiffOptions {
+        DiffOptions {
+            min_match_len: 8,
+            miss_skip: 1,
+            mismatch_budget: 8,
+            similarity_percent: 50,
+            prefer_nearby: false,
+            cost_aware: false,
+            limit: None,
+            new_offset: 0,
+            trace: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DiffStat {
     match_count: usize,
@@ -207,15 +888,17 @@ pub struct DiffStat {
     partial_match_length_sum: u64,
 }
 
-fn partial_match_length(a: &[u8], b: &[u8]) -> usize {
+/// Returns how far a partial match can be extended, and why scanning for more
+/// stopped.
+fn partial_match_length(a: &[u8], b: &[u8], options: &DiffOptions) -> (usize, StopReason) {
     let mut cur_matches = 0;
     let mut last_good_i = 0;
     let mut i = 0;
 
     let len = min(a.len(), b.len());
 
-    while (i - cur_matches < 8) && i < len {
-        if cur_matches >= i / 2 {
+    while (i - cur_matches < options.mismatch_budget) && i < len {
+        if cur_matches >= i * options.similarity_percent / 100 {
             last_good_i = i;
         }
 
@@ -226,18 +909,18 @@ fn partial_match_length(a: &[u8], b: &[u8]) -> usize {
         i += 1;
     }
 
-    last_good_i
+    (last_good_i, stop_reason(i, cur_matches, options))
 }
 
-fn reverse_partial_match_length(a: &[u8], b: &[u8]) -> usize {
+fn reverse_partial_match_length(a: &[u8], b: &[u8], options: &DiffOptions) -> (usize, StopReason) {
     let mut cur_matches = 0;
     let mut last_good_i = 0;
     let mut i = 0;
 
     let len = min(a.len(), b.len());
 
-    while (i - cur_matches < 8) && i < len {
-        if cur_matches >= i / 2 {
+    while (i - cur_matches < options.mismatch_budget) && i < len {
+        if cur_matches >= i * options.similarity_percent / 100 {
             last_good_i = i;
         }
 
@@ -248,11 +931,19 @@ fn reverse_partial_match_length(a: &[u8], b: &[u8]) -> usize {
         i += 1;
     }
 
-    last_good_i
+    (last_good_i, stop_reason(i, cur_matches, options))
+}
+
+fn stop_reason(scanned: usize, matches: usize, options: &DiffOptions) -> StopReason {
+    if scanned - matches >= options.mismatch_budget {
+        StopReason::MismatchBudget
+    } else {
+        StopReason::EndOfData
+    }
 }
 
 impl DiffStat {
-    pub fn from(old: &Index, new: &[u8]) -> DiffStat {
+    pub fn from<S: MatchStrategy>(strategy: &S, new: &[u8], options: &DiffOptions) -> DiffStat {
         let mut stat = DiffStat {
             match_count: 0,
             match_length_sum: 0,
@@ -260,7 +951,7 @@ impl DiffStat {
             partial_match_length_sum: 0,
         };
 
-        for m in MatchIter::from(old, new).map(|m| m.matched) {
+        for m in strategy.matches(new, options).map(|m| m.matched) {
             stat.match_count += 1;
             stat.match_length_sum += m.mid_exact_len as u64;
 
@@ -276,6 +967,9 @@ impl DiffStat {
 
 #[derive(Debug, Default, Eq, PartialEq)]
 pub struct Delta {
+    /// Which of the index's `references()` this delta reads from.  The
+    /// `old_offset` is still relative to the start of `Index::data`.
+    pub reference: usize,
     pub old_offset: usize,
     pub lower_delta_len: usize,
     pub mid_exact_len: usize,
@@ -302,43 +996,297 @@ pub struct Match {
     pub unmatched_suffix: usize,
 }
 
-pub struct MatchIter<'a> {
-    old: &'a Index,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Accepted,
+
+    /// The longest match was shorter than `min_match_len`.
+    TooShort,
+
+    /// Not searched, since an earlier search for the same bytes found no long
+    /// enough match.
+    KnownFailure,
+}
+
+/// Why a partial match extension stopped where it did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// It ran into `mismatch_budget` mismatched bytes.  The extension ends at
+    /// the last point where it was still `similarity_percent` similar.
+    MismatchBudget,
+
+    /// It reached the end of the reference in the old data, the end of the
+    /// new data, or (extending backwards) the end of the previous match.
+    EndOfData,
+
+    /// `cost_aware` cut it back to what's worth covering with a delta.
+    Cost,
+}
+
+/// A decision `MatchIter` made at a position in the new data, as reported to
+/// `DiffOptions::trace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub position: usize,
+
+    /// Where the longest match starts in the old data, or `None` if there was
+    /// no search.
+    pub old_offset: Option<usize>,
+    pub match_len: usize,
+
+    /// How far the match was extended by partial matching, backwards and
+    /// forwards.  Only accepted matches are extended.
+    pub lower_extension: usize,
+    pub upper_extension: usize,
+
+    /// Why each extension stopped, if the match was extended.
+    pub lower_stop: Option<StopReason>,
+    pub upper_stop: Option<StopReason>,
+
+    /// If the match wasn't accepted, how many bytes were passed over before
+    /// the next search: at least the match, and at least `miss_skip`.
+    pub skipped: usize,
+
+    pub outcome: Outcome,
+}
+
+impl Decision {
+    /// The bytes of the new data this decision accounted for: the extended
+    /// match if accepted, otherwise the bytes skipped over.
+    pub fn covers(&self) -> Range<usize> {
+        match self.outcome {
+            Outcome::Accepted =>
+                self.position - self.lower_extension .. self.position + self.match_len + self.upper_extension,
+            Outcome::TooShort | Outcome::KnownFailure =>
+                self.position .. self.position + self.skipped,
+        }
+    }
+}
+
+/// Writes a line for each decision that covers part of `range` of the new
+/// data.
+pub fn dump_trace<W: Write>(trace: &[Decision], range: Range<usize>, mut w: W) -> io::Result<()> {
+    for d in trace {
+        let covers = d.covers();
+        if covers.end <= range.start || covers.start >= range.end {
+            continue;
+        }
+
+        let old_offset = match d.old_offset {
+            Some(offset) => offset.to_string(),
+            None => "-".to_string(),
+        };
+
+        write!(w, "{:>10} {:?}: old {} len {} extended -{} +{}",
+            d.position, d.outcome, old_offset, d.match_len, d.lower_extension, d.upper_extension)?;
+
+        if let (Some(lower), Some(upper)) = (d.lower_stop, d.upper_stop) {
+            write!(w, ", stopped by {:?} / {:?}", lower, upper)?;
+        }
+
+        if d.outcome != Outcome::Accepted {
+            write!(w, ", skipped {}", d.skipped)?;
+        }
+
+        writeln!(w)?;
+    }
+
+    Ok(())
+}
+
+/// Number of slots in `MatchIter`'s cache of failed searches.
+const FAILED_SLOTS: usize = 256;
+
+pub struct MatchIter<'a, I: 'a = Index> {
+    old: &'a I,
     new: &'a [u8],
+    options: DiffOptions,
     i: usize,
     last_delta: Delta,
     last_end: usize,
+
+    /// Recent searches that found no match of `min_match_len` bytes, as
+    /// `(position in new, match length)`, indexed by a hash of the
+    /// `min_match_len` bytes at that position.
+    ///
+    /// A later position starting with the same bytes can't have a long enough
+    /// match either, so it's skipped without searching.  This is what keeps
+    /// runs and short repeating patterns that aren't in the old data from
+    /// costing a search per byte, much like bsdiff's skipping, but without
+    /// changing which matches are found.
+    failed: Vec<(usize, usize)>,
+
+    /// Set if `options.cost_aware`.
+    cost: Option<EntropyModel>,
 }
 
-impl<'a> MatchIter<'a> {
-    pub fn from(old: &'a Index, new: &'a [u8]) -> MatchIter<'a> {
+impl<'a, I: MatchIndex> MatchIter<'a, I> {
+    pub fn from(old: &'a I, new: &'a [u8]) -> MatchIter<'a, I> {
+        MatchIter::with_options(old, new, &Default::default())
+    }
+
+    pub fn with_options(old: &'a I, new: &'a [u8], options: &DiffOptions) -> MatchIter<'a, I> {
         MatchIter {
             old: old,
             new: new,
+            options: options.clone(),
             i: 0,
             last_delta: Default::default(),
             last_end: 0,
+            failed: vec![(usize::MAX, 0); FAILED_SLOTS],
+            cost: if options.cost_aware { Some(EntropyModel::new(new)) } else { None },
+        }
+    }
+
+    /// With `cost_aware`, cuts the partial match extensions of `m` back to
+    /// what's estimated to be worth covering with a delta, and records the
+    /// resulting delta bytes.
+    fn trim_extensions(&mut self, m: &Range<usize>, pml: usize, rpml: usize) -> (usize, usize) {
+        let old = self.old.data();
+        let new = self.new;
+        let i = self.i;
+
+        let model = match self.cost {
+            Some(ref mut model) => model,
+            None => return (pml, rpml),
+        };
+
+        let pml = model.worth_covering((0..pml).map(|k| (old[m.end + k], new[i + m.len() + k])));
+        let rpml = model.worth_covering((1..rpml + 1).map(|k| (old[m.start - k], new[i - k])));
+
+        model.record_delta(&old[m.start - rpml..m.start], &new[i - rpml..i]);
+        model.record_exact(m.len());
+        model.record_delta(&old[m.end..m.end + pml], &new[i + m.len()..i + m.len() + pml]);
+
+        (pml, rpml)
+    }
+
+    /// Reports `decision` to the trace hook, with its position in the whole
+    /// new data.
+    fn record(&self, decision: Decision) {
+        if let Some(ref hook) = self.options.trace {
+            (hook.0)(&Decision {
+                position: self.options.new_offset + decision.position,
+                ..decision
+            });
+        }
+    }
+
+    /// How far to move on after a search at `i` found a match of `len`
+    /// bytes that's too short.
+    fn miss_skip(&self, len: usize) -> usize {
+        min(max(self.options.miss_skip, max(1, len)), self.new.len() - self.i)
+    }
+
+    fn failed_slot(&self, i: usize) -> Option<usize> {
+        let key_len = self.options.min_match_len;
+        if key_len == 0 || self.new.len() - i < key_len {
+            return None;
+        }
+
+        // FNV-1a
+        let mut hash = 0xcbf29ce484222325u64;
+        for &byte in &self.new[i..i + key_len] {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+
+        Some(hash as usize % FAILED_SLOTS)
+    }
+
+    /// Returns the match length of an earlier failed search for the same
+    /// bytes as at `i`, if there was one.
+    fn known_failure(&self, i: usize) -> Option<usize> {
+        let slot = self.failed_slot(i)?;
+        let (pos, len) = self.failed[slot];
+        let key_len = self.options.min_match_len;
+
+        if pos != usize::MAX && self.new[pos..pos + key_len] == self.new[i..i + key_len] {
+            Some(len)
+        } else {
+            None
+        }
+    }
+
+    fn remember_failure(&mut self, i: usize, len: usize) {
+        if let Some(slot) = self.failed_slot(i) {
+            self.failed[slot] = (i, len);
         }
     }
 }
 
-impl<'a> Iterator for MatchIter<'a> {
+impl<'a, I: MatchIndex> Iterator for MatchIter<'a, I> {
     type Item = Match;
     
     fn next(&mut self) -> Option<Self::Item> {
         while self.i < self.new.len() {
-            let m = self.old.longest_match(&self.new[self.i..]);
+            if let Some(len) = self.known_failure(self.i) {
+                let skipped = self.miss_skip(len);
+                self.record(Decision {
+                    position: self.i,
+                    old_offset: None,
+                    match_len: len,
+                    lower_extension: 0,
+                    upper_extension: 0,
+                    lower_stop: None,
+                    upper_stop: None,
+                    skipped: skipped,
+                    outcome: Outcome::KnownFailure,
+                });
+
+                self.i += skipped;
+                continue;
+            }
+
+            if let Some(ref limit) = self.options.limit {
+                if !limit.spend() {
+                    limit.stop(self.options.new_offset + self.i);
+                    self.i = self.new.len();
+                    break;
+                }
+            }
+
+            let m = if self.options.prefer_nearby {
+                let hint = self.last_delta.old_offset + self.last_delta.len();
+                self.old.longest_match_near(&self.new[self.i..], hint)
+            } else {
+                self.old.longest_match(&self.new[self.i..])
+            };
 
             // println!("i {} match {:?}", self.i, m);
 
-            if m.len() >= 8 {
-                let pml = partial_match_length(
-                    &self.old.data[m.end..],
-                    &self.new[self.i + m.len()..]);
+            if m.len() >= self.options.min_match_len {
+                let (reference, bounds) = self.old.reference_bounds(m.start);
 
-                let rpml = reverse_partial_match_length(
-                    &self.old.data[..m.start],
-                    &self.new[self.last_end..self.i]);
+                let (pml, mut upper_stop) = partial_match_length(
+                    &self.old.data()[m.end..bounds.end],
+                    &self.new[self.i + m.len()..],
+                    &self.options);
+
+                let (rpml, mut lower_stop) = reverse_partial_match_length(
+                    &self.old.data()[bounds.start..m.start],
+                    &self.new[self.last_end..self.i],
+                    &self.options);
+
+                let (trimmed_pml, trimmed_rpml) = self.trim_extensions(&m, pml, rpml);
+                if trimmed_pml < pml {
+                    upper_stop = StopReason::Cost;
+                }
+                if trimmed_rpml < rpml {
+                    lower_stop = StopReason::Cost;
+                }
+                let (pml, rpml) = (trimmed_pml, trimmed_rpml);
+
+                self.record(Decision {
+                    position: self.i,
+                    old_offset: Some(m.start),
+                    match_len: m.len(),
+                    lower_extension: rpml,
+                    upper_extension: pml,
+                    lower_stop: Some(lower_stop),
+                    upper_stop: Some(upper_stop),
+                    skipped: 0,
+                    outcome: Outcome::Accepted,
+                });
 
                 let begin = self.i - rpml;
 
@@ -348,20 +1296,37 @@ impl<'a> Iterator for MatchIter<'a> {
                 self.i += max(1, m.len() + pml) as usize;
 
                 let last_delta = mem::replace(&mut self.last_delta, Delta {
+                    reference: reference,
                     old_offset: m.start - rpml,
                     lower_delta_len: rpml,
                     mid_exact_len: m.len(),
                     upper_delta_len: pml,
                 });
 
-                if self.i > self.last_end || last_delta.len() > 0 {
+                // Skip the empty placeholder before the first match, unless
+                // there are unmatched bytes before it that need emitting.
+                if begin > last_end || last_delta.len() > 0 {
                     return Some(Match {
                         matched: last_delta,
                         unmatched_suffix: begin - last_end,
                     });
                 }
             } else {
-                self.i += max(1, m.len()) as usize;
+                let skipped = self.miss_skip(m.len());
+                self.record(Decision {
+                    position: self.i,
+                    old_offset: Some(m.start),
+                    match_len: m.len(),
+                    lower_extension: 0,
+                    upper_extension: 0,
+                    lower_stop: None,
+                    upper_stop: None,
+                    skipped: skipped,
+                    outcome: Outcome::TooShort,
+                });
+
+                self.remember_failure(self.i, m.len());
+                self.i += skipped;
             }
         }
 
@@ -410,7 +1375,286 @@ pub fn write_delta<W: Write>(mut w: W, old: &[u8], new: &[u8]) -> io::Result<()>
 
 #[cfg(test)]
 mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+
     use super::*;
+    use test_util::{assert_roundtrip, pseudo_random, Lcg, OLD};
+
+    #[derive(Clone)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct MemCache {
+        entries: RefCell<HashMap<[u8; 20], SharedBuf>>,
+        compression_level: Option<i32>,
+    }
+
+    impl<'a> Cache for &'a MemCache {
+        type Read = Cursor<Vec<u8>>;
+        type Write = SharedBuf;
+
+        fn get(&self, digest: &[u8; 20]) -> io::Result<Option<Self::Read>> {
+            Ok(self.entries.borrow().get(digest).map(|b| Cursor::new(b.0.borrow().clone())))
+        }
+
+        fn get_writer(&self, digest: &[u8; 20]) -> io::Result<Self::Write> {
+            let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+            self.entries.borrow_mut().insert(*digest, buf.clone());
+            Ok(buf)
+        }
+
+        fn compression_level(&self) -> Option<i32> {
+            self.compression_level
+        }
+    }
+
+    fn offsets_of(index: &Index) -> Vec<usize> {
+        (0..index.offsets.len()).map(|i| index.offsets.get(i)).collect()
+    }
+
+    #[test]
+    fn test_lcp_longest_match() {
+        let mut data = Lcg(1).take(4000).map(|x| b"abc"[(x >> 16) as usize % 3]).collect::<Vec<_>>();
+        data.extend_from_slice(&[0u8; 100]);
+
+        let plain = Index::compute(data.clone());
+        let mut fast = Index::compute(data.clone());
+        fast.build_lcp();
+
+        let mut queries = vec![
+            b"".to_vec(),
+            b"abcabcabc".to_vec(),
+            b"zzz".to_vec(),
+            b"\0\0\0\0\0\0\0\0\0\0\0\0".to_vec(),
+            data.clone(),
+        ];
+        for i in 0..200 {
+            let start = (i * 37) % data.len();
+            let mut q = data[start..min(data.len(), start + i)].to_vec();
+            q.push(b"abcd"[i % 4]);
+            queries.push(q);
+        }
+
+        for q in &queries {
+            assert_eq!(plain.longest_match(q), fast.longest_match(q));
+        }
+    }
+
+    #[test]
+    fn test_multi_reference_repetitive_matches() {
+        // Every reference is the same short pattern, and shorter than the
+        // queries, so thousands of suffixes match well past the end of their
+        // reference.
+        let references = (0..40).map(|k| {
+            (k.to_string(), b"abc".iter().cycle().skip(k % 3).take(500 + k).cloned().collect::<Vec<_>>())
+        }).collect();
+        let mut index = Index::compute_multi(references);
+
+        let queries = vec![
+            b"abc".iter().cycle().take(2000).cloned().collect::<Vec<_>>(),
+            b"cab".iter().cycle().take(520).chain(b"x").cloned().collect::<Vec<_>>(),
+            b"bcab".to_vec(),
+            b"abcx".to_vec(),
+        ];
+
+        let expected = |buf: &[u8]| {
+            index.references().iter().flat_map(|r| {
+                r.range.clone().map(move |start| (start, r.range.end))
+            }).map(|(start, end)| min(longest_prefix(buf, &index.data[start..]), end - start)).max().unwrap()
+        };
+        let expected = queries.iter().map(|q| expected(q)).collect::<Vec<_>>();
+        assert_eq!(expected, vec![539, 520, 4, 3]);
+
+        for &lcp in &[false, true] {
+            if lcp {
+                index.build_lcp();
+            }
+
+            for (q, &len) in queries.iter().zip(&expected) {
+                let m = index.longest_match(q);
+                assert_eq!(m.len(), len);
+                assert_eq!(&index.data[m.clone()], &q[..len]);
+                assert_eq!(index.reference_at(m.start), index.reference_at(m.end - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_multi_reference_matches_stay_within_references() {
+        let index = Index::compute_multi(vec![
+            ("first".to_string(), b"0123456789abcdef".to_vec()),
+            ("second".to_string(), b"ghijklmnopqrstuv".to_vec()),
+        ]);
+
+        assert_eq!(index.references()[1], Reference {
+            name: "second".to_string(),
+            range: 16 .. 32,
+        });
+
+        assert_eq!(index.longest_match(b"cdefghij"), 12 .. 16);
+
+        let matches = MatchIter::from(&index, b"89abcdefghijklmn").collect::<Vec<_>>();
+
+        assert_eq!(matches, vec![
+            Match {
+                matched: Delta {
+                    reference: 0,
+                    old_offset: 8,
+                    lower_delta_len: 0,
+                    mid_exact_len: 8,
+                    upper_delta_len: 0,
+                },
+                unmatched_suffix: 0
+            },
+            Match {
+                matched: Delta {
+                    reference: 1,
+                    old_offset: 16,
+                    lower_delta_len: 0,
+                    mid_exact_len: 8,
+                    upper_delta_len: 0,
+                },
+                unmatched_suffix: 0
+            },
+        ]);
+    }
+
+    /// Keeps entries as files, so that they're memory-mapped.
+    struct DirCache(::std::path::PathBuf);
+
+    impl<'a> Cache for &'a DirCache {
+        type Read = File;
+        type Write = File;
+
+        fn get(&self, digest: &[u8; 20]) -> io::Result<Option<File>> {
+            match File::open(self.0.join(format!("{:?}", digest[0]))) {
+                Ok(file) => Ok(Some(file)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        fn get_writer(&self, digest: &[u8; 20]) -> io::Result<File> {
+            File::create(self.0.join(format!("{:?}", digest[0])))
+        }
+
+        fn get_file(&self, digest: &[u8; 20]) -> io::Result<Option<File>> {
+            self.get(digest)
+        }
+    }
+
+    #[test]
+    fn test_mapped_entry_corrupted_in_place_is_rebuilt() {
+        use std::fs::{self, OpenOptions};
+        use std::io::{Seek, SeekFrom};
+
+        let dir = ::std::env::temp_dir().join("rsdiff-test-mapped-corrupt");
+        fs::create_dir_all(&dir).unwrap();
+        let cache = DirCache(dir.clone());
+
+        let data = b"this is a test 12345678 test".to_vec();
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let computed = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+
+        // Point the last offset far past the end of the data.
+        let path = dir.join(format!("{:?}", digest(&data)[0]));
+        {
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            let len = file.metadata().unwrap().len();
+            file.seek(SeekFrom::Start(len - index::TRAILER_SIZE as u64 - 4)).unwrap();
+            file.write_all(&[0xff, 0xff, 0, 0]).unwrap();
+        }
+
+        let rebuilt = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+        assert_eq!(offsets_of(&computed), offsets_of(&rebuilt));
+        assert_eq!(MatchIter::from(&rebuilt, new).collect::<Vec<_>>(), MatchIter::from(&computed, new).collect::<Vec<_>>());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_cache_roundtrip() {
+        let cache = MemCache {
+            compression_level: Some(3),
+            ..Default::default()
+        };
+        let data = b"this is a test 12345678 test".to_vec();
+
+        let computed = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+        let loaded = Index::from_cache_or_compute(&cache, data).unwrap();
+        assert_eq!(offsets_of(&computed), offsets_of(&loaded));
+    }
+
+    #[test]
+    fn test_corrupt_cache_entry_is_rebuilt() {
+        let cache = MemCache::default();
+        let data = b"this is a test 12345678 test".to_vec();
+
+        let computed = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+
+        // Simulate a writer that was killed part way through the table.
+        for buf in cache.entries.borrow().values() {
+            let len = buf.0.borrow().len();
+            buf.0.borrow_mut().truncate(len - 10);
+        }
+
+        let rebuilt = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+        assert_eq!(offsets_of(&computed), offsets_of(&rebuilt));
+
+        // The entry was overwritten with a good one.
+        let digest = digest(&data);
+        let r = (&cache).get(&digest).unwrap().unwrap();
+        assert!(index::read_offsets(r, &digest, data.len()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_cache_append() {
+        let cache = MemCache::default();
+        let parent = b"this is a test 12345678 test".to_vec();
+        let mut data = parent.clone();
+        data.extend_from_slice(b" and some more test");
+
+        Index::from_cache_or_compute(&cache, parent.clone()).unwrap();
+
+        let appended = Index::from_cache_or_append(&cache, data.clone(), parent.len()).unwrap();
+        assert_eq!(cache.entries.borrow().len(), 2);
+        assert_eq!(offsets_of(&Index::compute(data.clone())), offsets_of(&appended));
+
+        let loaded = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+        assert_eq!(offsets_of(&appended), offsets_of(&loaded));
+
+        match Index::from_cache_or_append(&cache, parent.clone(), data.len()) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("parent longer than data was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let cache = MemCache::default();
+        let data = b"this is a test 12345678 test".to_vec();
+
+        let computed = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+        assert_eq!(cache.entries.borrow().len(), 1);
+
+        let loaded = Index::from_cache_or_compute(&cache, data).unwrap();
+        assert_eq!(loaded.offsets.width(), 4);
+        assert_eq!(offsets_of(&computed), offsets_of(&loaded));
+    }
 
     #[test]
     fn test_index_simple_match() {
@@ -421,6 +1665,7 @@ mod tests {
         assert_eq!(matches, vec![
             Match {
                 matched: Delta {
+                    reference: 0,
                     old_offset: 0,
                     lower_delta_len: 0,
                     mid_exact_len: 14,
@@ -430,12 +1675,262 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_longest_match_near() {
+        let mut data = b"0123456789abcdef".to_vec();
+        data.extend((0..100).map(|_| b'x'));
+        data.extend_from_slice(b"0123456789abcdef");
+        data.extend((0..100).map(|_| b'y'));
+        data.extend_from_slice(b"0123456789abcdeX");
+
+        for &lcp in &[false, true] {
+            let mut index = Index::compute(data.clone());
+            if lcp {
+                index.build_lcp();
+            }
+
+            assert_eq!(index.longest_match_near(b"0123456789abcdef", 0), 0..16);
+            assert_eq!(index.longest_match_near(b"0123456789abcdef", 130), 116..132);
+            assert_eq!(index.longest_match_near(b"0123456789abcdef", 1000), 116..132);
+            assert_eq!(index.longest_match_near(b"0123456789abcdeX", 0), 232..248);
+            assert_eq!(index.longest_match_near(b"0123456789abcd!", 1000).start, 232);
+        }
+    }
+
+    #[test]
+    fn test_matches_cover_leading_garbage() {
+        let mut new = b"XXXXXXXXXXXX".to_vec();
+        new.extend_from_slice(OLD);
+
+        let index = Index::compute(OLD.to_vec());
+        let matches = MatchIter::from(&index, &new).collect::<Vec<_>>();
+
+        assert_eq!(matches[0], Match {
+            matched: Default::default(),
+            unmatched_suffix: 12,
+        });
+        assert_eq!(matches.iter().map(|m| m.matched.len() + m.unmatched_suffix).sum::<usize>(), new.len());
+    }
+
+    #[test]
+    fn test_options_min_match_len() {
+        let index = Index::compute(Vec::from(&b"this is a test"[..]));
+
+        let options = DiffOptions {
+            min_match_len: 15,
+            ..Default::default()
+        };
+        let matches = MatchIter::with_options(&index, b"this is a test", &options).collect::<Vec<_>>();
+
+        assert_eq!(matches, vec![
+            Match {
+                matched: Default::default(),
+                unmatched_suffix: 14,
+            }
+        ]);
+    }
+
+    #[test]
+    fn test_trace() {
+        let index = Index::compute(b"abcdefg_________0123456789abcdef".to_vec());
+        let new = b"abcdefgXX0123456789abcdeX";
+
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let options = DiffOptions {
+            trace: Some({
+                let trace = trace.clone();
+                TraceHook::new(move |d: &Decision| trace.lock().unwrap().push(d.clone()))
+            }),
+            ..Default::default()
+        };
+
+        let patch = ::format::bsdiff::generate_full_patch(&index, new, &options);
+        assert_eq!(patch, ::format::bsdiff::generate_full_patch(&index, new, &Default::default()));
+
+        let trace = trace.lock().unwrap();
+
+        // "abcdefg" is too short to match on its own, and most of it is only
+        // covered by extending the match for "0123456789abcde" backwards.
+        assert_eq!(trace[0], Decision {
+            position: 0,
+            old_offset: Some(0),
+            match_len: 7,
+            lower_extension: 0,
+            upper_extension: 0,
+            lower_stop: None,
+            upper_stop: None,
+            skipped: 7,
+            outcome: Outcome::TooShort,
+        });
+
+        let mut dump = Vec::new();
+        dump_trace(&trace, 9..10, &mut dump).unwrap();
+        assert_eq!(str::from_utf8(&dump).unwrap(),
+            "         9 Accepted: old 16 len 15 extended -8 +0, stopped by EndOfData / EndOfData\n");
+    }
+
+    #[test]
+    fn test_trace_covers_skipped_bytes() {
+        let mut new = Vec::new();
+        let mut random = Lcg(5);
+        while new.len() < 500 {
+            new.extend(random.by_ref().take(40).map(|x| (x >> 16) as u8));
+            new.extend_from_slice(&OLD[..40]);
+        }
+        let index = Index::compute(OLD.to_vec());
+
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let options = DiffOptions {
+            trace: Some({
+                let trace = trace.clone();
+                TraceHook::new(move |d: &Decision| trace.lock().unwrap().push(d.clone()))
+            }),
+            ..DiffOptions::fast()
+        };
+        MatchIter::with_options(&index, &new, &options).count();
+
+        // Every byte is accounted for by some decision, skipped over or not.
+        let trace = trace.lock().unwrap();
+        let mut covered = vec![false; new.len()];
+        for d in trace.iter() {
+            for i in d.covers() {
+                covered[i] = true;
+            }
+        }
+        assert_eq!(covered.iter().filter(|&&c| !c).count(), 0);
+        assert!(trace.iter().any(|d| d.skipped == options.miss_skip));
+
+        let mut dump = Vec::new();
+        dump_trace(&trace, 3..4, &mut dump).unwrap();
+        assert_eq!(str::from_utf8(&dump).unwrap(), "         0 TooShort: old 64 len 0 extended -0 +0, skipped 8\n");
+    }
+
+    #[test]
+    fn test_cost_aware_trims_weak_extension() {
+        let old = pseudo_random(128, 7).into_iter().map(|b| b | 0x80).collect::<Vec<_>>();
+
+        // After an exact match, every other byte still matches, but the rest
+        // are a byte that's common in the new file, so cheap as extra data.
+        let mut new = old[..64].to_vec();
+        new.extend(old[64..].iter().enumerate().map(|(k, &b)| if k % 2 == 0 { b } else { b'a' }));
+        new.extend_from_slice(&[b'a'; 400]);
+
+        let index = Index::compute(old.clone());
+        let plain = DiffOptions::default();
+        let cost_aware = DiffOptions {
+            cost_aware: true,
+            ..plain.clone()
+        };
+
+        let first_upper = |options: &DiffOptions| {
+            MatchIter::with_options(&index, &new, options)
+                .map(|m| m.matched)
+                .find(|d| d.len() > 0)
+                .unwrap()
+                .upper_delta_len
+        };
+        assert!(first_upper(&cost_aware) < first_upper(&plain));
+
+        let stops = Arc::new(Mutex::new(Vec::new()));
+        let traced = |options: &DiffOptions| {
+            let stops = stops.clone();
+            stops.lock().unwrap().clear();
+            let options = DiffOptions {
+                trace: Some(TraceHook::new(move |d: &Decision| stops.lock().unwrap().push(d.upper_stop))),
+                ..options.clone()
+            };
+            MatchIter::with_options(&index, &new, &options).count();
+        };
+
+        traced(&plain);
+        assert_eq!(stops.lock().unwrap()[0], Some(StopReason::MismatchBudget));
+        traced(&cost_aware);
+        assert_eq!(stops.lock().unwrap()[0], Some(StopReason::Cost));
+
+        let plain_patch = ::format::bsdiff::generate_full_patch(&index, &new, &plain);
+        let patch = assert_roundtrip(&index, &new, &cost_aware);
+        assert!(patch.len() < plain_patch.len(), "{} vs {}", patch.len(), plain_patch.len());
+    }
+
+    struct CountingIndex<'a> {
+        index: &'a Index,
+        searches: Cell<usize>,
+    }
+
+    impl<'a> MatchIndex for CountingIndex<'a> {
+        fn data(&self) -> &[u8] {
+            &self.index.data
+        }
+
+        fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+            self.searches.set(self.searches.get() + 1);
+            self.index.longest_match(buf)
+        }
+    }
+
+    #[test]
+    fn test_repetitive_input_skips_searches() {
+        let old = b"some old data with a short run 00000 and then xyzxyzx, and the end".to_vec();
+        let index = Index::compute(old.clone());
+
+        let mut new = b"some old data".to_vec();
+        new.extend((0..100000).map(|_| b'0'));
+        new.extend(b"xyz".iter().cycle().take(30000));
+        new.extend_from_slice(b" with a short run 00000 and then xyzxyzx, and the end");
+
+        let counting = CountingIndex {
+            index: &index,
+            searches: Cell::new(0),
+        };
+
+        MatchIter::from(&counting, &new).count();
+        assert!(counting.searches.get() < 1000, "{} searches", counting.searches.get());
+
+        assert_roundtrip(&index, &new, &Default::default());
+    }
+
+    #[test]
+    fn test_fast_options_search_less() {
+        // Mostly unrelated data, with a few long runs copied from old.
+        let old = pseudo_random(1 << 16, 1);
+        let mut new = Vec::new();
+        for k in 0..4 {
+            new.extend(pseudo_random(5000, 2 + k));
+            new.extend_from_slice(&old[k as usize * 10000..k as usize * 10000 + 300]);
+        }
+
+        let index = Index::compute(old.clone());
+        let counting = CountingIndex {
+            index: &index,
+            searches: Cell::new(0),
+        };
+
+        let searches_and_matched = |options: &DiffOptions| {
+            counting.searches.set(0);
+            let matched = MatchIter::with_options(&counting, &new, options).map(|m| m.matched.len()).sum::<usize>();
+            (counting.searches.get(), matched)
+        };
+
+        let (default_searches, default_matched) = searches_and_matched(&DiffOptions::default());
+        let (fast_searches, fast_matched) = searches_and_matched(&DiffOptions::fast());
+
+        assert!(fast_searches * 4 < default_searches, "{} vs {} searches", fast_searches, default_searches);
+
+        // The copied runs are still found, even if their first few bytes
+        // are skipped over.
+        assert!(default_matched >= 4 * 300);
+        assert!(fast_matched >= 4 * (300 - 8), "matched {}", fast_matched);
+
+        assert_roundtrip(&index, &new, &DiffOptions::fast());
+    }
+
     #[test]
     fn test_index_slightly_less_simple_match() {
         let index = Index::compute(Vec::from(&b"this is a test 12345678 test"[..]));
 
         println!("index:");
-        for (i, &offset) in index.offsets.iter().enumerate() {
+        for i in 0..index.offsets.len() {
+            let offset = index.offsets.get(i);
             println!("  {}:  {}: {:?}", i, offset, ::std::str::from_utf8(&index.data[offset..]).unwrap());
         }
 
@@ -448,6 +1943,7 @@ mod tests {
         assert_eq!(matches, vec![
             Match {
                 matched: Delta {
+                    reference: 0,
                     old_offset: 0,
                     lower_delta_len: 0,
                     mid_exact_len: 8,
@@ -459,6 +1955,7 @@ mod tests {
             // We can probably do a better job here.
             Match {
                 matched: Delta {
+                    reference: 0,
                     old_offset: 13,
                     lower_delta_len: 1,
                     mid_exact_len: 10,
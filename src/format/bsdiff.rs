@@ -9,11 +9,14 @@ use bzip2::bufread::BzDecoder;
 use bzip2;
 
 use diff::{
+    DiffOptions,
     Index,
+    Match,
+    MatchStrategy,
     write_delta,
     write_zeros,
-    MatchIter,
 };
+use matcher::bsdiff43::Bsdiff43;
 
 use patch::{
     read_paired_bufs,
@@ -297,14 +300,33 @@ pub fn generate_idempotent_patch(desired_output: &[u8]) -> Vec<u8> {
     w.finish()
 }
 
-pub fn generate_full_patch(old: &Index, new: &[u8]) -> Vec<u8> {
+pub fn generate_full_patch<S: MatchStrategy>(strategy: &S, new: &[u8], options: &DiffOptions) -> Vec<u8> {
+    write_patch(strategy.old(), new, strategy.matches(new, options), None)
+}
+
+/// Generates the same patch as Colin Percival's bsdiff 4.3, using its match
+/// selection (see `Bsdiff43`).  Unlike `generate_full_patch` with a
+/// `Bsdiff43Strategy`, this also reproduces bsdiff's final seek.
+pub fn generate_bsdiff43_patch(old: &Index, new: &[u8]) -> Vec<u8> {
+    let mut matcher = Bsdiff43::from(old, new);
+    let matches = matcher.by_ref().collect::<Vec<_>>();
+
+    write_patch(&old.data, new, matches.into_iter(), Some(matcher.final_old_offset()))
+}
+
+/// Writes a patch made of the given matches.  Each command seeks the old file
+/// to the start of the next match; the last one seeks to `final_old_offset`,
+/// or not at all if that's `None`.
+fn write_patch<M>(old: &[u8], new: &[u8], matches: M, final_old_offset: Option<usize>) -> Vec<u8>
+    where M: Iterator<Item=Match>
+{
     let mut w = PatchWriter::new(new.len());
 
     let mut i = 0;
 
     let mut k = 0;
 
-    let mut it = MatchIter::from(old, new).peekable();
+    let mut it = matches.peekable();
 
 
     while let Some(m) = it.next() {
@@ -318,6 +340,7 @@ pub fn generate_full_patch(old: &Index, new: &[u8]) -> Vec<u8> {
         let mm = m.matched;
         let next_old_offset = it.peek()
             .map(|m| m.matched.old_offset)
+            .or(final_old_offset)
             .unwrap_or(mm.old_offset + mm.len());
 
         w.write_command(&Command {
@@ -327,13 +350,13 @@ pub fn generate_full_patch(old: &Index, new: &[u8]) -> Vec<u8> {
         });
 
         w.write_delta(
-            &old.data[mm.lower_delta_range()], 
+            &old[mm.lower_delta_range()], 
             &new[i .. i + mm.lower_delta_len]);
 
         w.write_delta_zeros(mm.mid_exact_len);
 
         w.write_delta(
-            &old.data[mm.upper_delta_range()], 
+            &old[mm.upper_delta_range()], 
             &new[i + mm.lower_delta_len + mm.mid_exact_len .. i + mm.len()]);
 
         let extra_begin = i + mm.len();
@@ -490,7 +513,7 @@ mod tests {
         let buf = b"this is a test";
         let buf2 = b"this is really a cool test";
         let index = Index::compute(buf.to_vec());
-        let patch = generate_full_patch(&index, &buf2[..]);
+        let patch = generate_full_patch(&index, &buf2[..], &Default::default());
         
         let mut new = Vec::new();
         let mut old = Cursor::new(buf);
@@ -505,7 +528,7 @@ mod tests {
         let buf = b"this is a test 12345678 test";
         let buf2 = b"this is really a cool uftu 12345678 uftu";
         let index = Index::compute(buf.to_vec());
-        let patch = generate_full_patch(&index, &buf2[..]);
+        let patch = generate_full_patch(&index, &buf2[..], &Default::default());
         
         let mut new = Vec::new();
         let mut old = Cursor::new(buf);
@@ -515,4 +538,53 @@ mod tests {
 
         assert_eq!(str::from_utf8(buf2).unwrap(), str::from_utf8(&new).unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_bsdiff43_patch() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"this is a test 12345678 test", b"this is really a cool uftu 12345678 uftu"),
+            (b"abcdefghijklmnop", b"abcdefghijklmnop"),
+            (b"", b"something from nothing"),
+            (b"something", b""),
+            (b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", b"aaaaaaaaaaaabaaaaaaaaaaaaaaaaaaaaaaaa"),
+        ];
+
+        for &(buf, buf2) in cases {
+            let index = Index::compute(buf.to_vec());
+            let patch = generate_bsdiff43_patch(&index, buf2);
+
+            let mut new = Vec::new();
+            apply_patch(&patch, Cursor::new(buf), &mut new).unwrap();
+
+            assert_eq!(&buf2[..], &new[..]);
+        }
+
+        // bsdiff 4.3 ends an unchanged file with a seek back to the start.
+        let buf = b"abcdefghijklmnop";
+        let patch = generate_bsdiff43_patch(&Index::compute(buf.to_vec()), buf);
+        let header = Header::read(&patch[..32]).unwrap();
+        let commands = BzDecoder::new(Cursor::new(&patch[32..32 + header.compressed_commands_size as usize]));
+
+        assert_eq!(CommandReader::new(commands).map(|c| c.unwrap()).collect::<Vec<_>>(), vec![Command {
+            bytewise_add_size: 16,
+            extra_append_size: 0,
+            oldfile_seek_offset: -16,
+        }]);
+    }
+
+    #[test]
+    fn test_bsdiff43_golden() {
+        // Patches made by the reference bsdiff 4.3 (built against libbz2).
+        let cases: &[(&[u8], &[u8], &[u8])] = &[
+            (include_bytes!("testdata/text.old"), include_bytes!("testdata/text.new"),
+                include_bytes!("testdata/text.bsdiff43")),
+            (include_bytes!("testdata/binary.old"), include_bytes!("testdata/binary.new"),
+                include_bytes!("testdata/binary.bsdiff43")),
+        ];
+
+        for &(old, new, expected) in cases {
+            let patch = generate_bsdiff43_patch(&Index::compute(old.to_vec()), new);
+            assert!(&patch[..] == expected);
+        }
+    }
+}
@@ -0,0 +1,631 @@
+use std::cmp::min;
+use std::io::{self, Read, Write, BufReader, BufWriter, Cursor};
+use std::fs::File;
+
+use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt, ByteOrder};
+use memmap::{Mmap, Protection};
+use sha1::Sha1;
+use zstd;
+
+use suffix_array::{Offsets, MappedOffsets};
+
+pub const MAGIC: &'static [u8; 8] = b"RSDIFFIX";
+
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The offset table is a single zstd stream.
+pub const FLAG_ZSTD: u8 = 0x1;
+
+/// Each table entry is stored as a zigzag-encoded LEB128 varint holding the
+/// difference from the previous entry, instead of as a fixed-width integer.
+pub const FLAG_DELTA: u8 = 0x2;
+
+/// Size of the fixed header.  This is a multiple of 8, so the offset table
+/// that follows it is aligned for either offset width.
+pub const HEADER_SIZE: usize = 48;
+
+/// Size of the trailer after the offset table: the table's length in bytes
+/// (u64), then the SHA-1 of those bytes.  It's written last, so an entry cut
+/// short by a killed process is detected rather than misread.
+pub const TRAILER_SIZE: usize = 28;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Header {
+    // NOTE: there's a non-stored field: magic (always b"RSDIFFIX")
+
+    pub version: u32,
+
+    /// Size in bytes of each entry in the offset table: 4 or 8.
+    pub offset_width: u8,
+
+    /// Some combination of `FLAG_ZSTD` and `FLAG_DELTA`.  Only a table with no
+    /// flags set can be memory-mapped.
+    pub flags: u8,
+
+    /// Length of the indexed data, which is also the number of table entries.
+    pub data_len: u64,
+
+    pub digest: [u8; 20],
+
+    // NOTE: the header is zero-padded to HEADER_SIZE, and the offset table
+    // (`data_len` little-endian entries of `offset_width` bytes, unless flags
+    // are set) follows it, then the trailer.
+}
+
+impl Header {
+    pub fn read(buf: &[u8]) -> io::Result<Header> {
+        if buf.len() < HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated index header"));
+        }
+
+        if &buf[0..8] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad index header"));
+        }
+
+        let mut digest = [0u8; 20];
+        digest.copy_from_slice(&buf[24..44]);
+
+        Ok(Header {
+            version: LittleEndian::read_u32(&buf[8..12]),
+            offset_width: buf[12],
+            flags: buf[13],
+            data_len: LittleEndian::read_u64(&buf[16..24]),
+            digest: digest,
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut buf = [0u8; HEADER_SIZE];
+
+        buf[0..8].copy_from_slice(MAGIC);
+        LittleEndian::write_u32(&mut buf[8..12], self.version);
+        buf[12] = self.offset_width;
+        buf[13] = self.flags;
+        LittleEndian::write_u64(&mut buf[16..24], self.data_len);
+        buf[24..44].copy_from_slice(&self.digest);
+
+        writer.write_all(&buf)
+    }
+
+    /// Whether this header describes an index of `data_len` bytes of data with
+    /// the given digest, in a format we know how to read.
+    fn describes(&self, digest: &[u8; 20], data_len: usize) -> bool {
+        self.version == FORMAT_VERSION &&
+            (self.offset_width == 4 || self.offset_width == 8) &&
+            self.flags & !(FLAG_ZSTD | FLAG_DELTA) == 0 &&
+            self.data_len == data_len as u64 &&
+            &self.digest == digest
+    }
+}
+
+/// Passes writes through, keeping a running length and SHA-1 of everything
+/// written for the trailer.
+struct Checksummed<W> {
+    inner: W,
+    len: u64,
+    sha1: Sha1,
+}
+
+impl<W: Write> Checksummed<W> {
+    fn new(inner: W) -> Checksummed<W> {
+        Checksummed {
+            inner: inner,
+            len: 0,
+            sha1: Sha1::new(),
+        }
+    }
+
+    fn write_trailer(self) -> io::Result<W> {
+        let mut w = self.inner;
+        w.write_u64::<LittleEndian>(self.len)?;
+        w.write_all(&self.sha1.digest().bytes())?;
+        Ok(w)
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        self.sha1.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Checks a trailer against the length and SHA-1 of the table it follows.
+fn check_trailer(trailer: &[u8], table_len: u64, sha1: &Sha1) -> io::Result<()> {
+    if trailer.len() < TRAILER_SIZE || LittleEndian::read_u64(&trailer[..8]) != table_len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated index table"));
+    }
+
+    if &sha1.digest().bytes()[..] != &trailer[8..] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Index table checksum mismatch"));
+    }
+
+    Ok(())
+}
+
+/// Splits `rest` (everything after the header) into the table and trailer,
+/// checking the trailer's length field, and its checksum if `verify` is set.
+fn split_trailer(rest: &[u8], verify: bool) -> io::Result<&[u8]> {
+    if rest.len() < TRAILER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated index table"));
+    }
+
+    let (table, trailer) = rest.split_at(rest.len() - TRAILER_SIZE);
+
+    if verify {
+        let mut sha1 = Sha1::new();
+        sha1.update(table);
+        check_trailer(trailer, table.len() as u64, &sha1)?;
+    } else if LittleEndian::read_u64(&trailer[..8]) != table.len() as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated index table"));
+    }
+
+    Ok(table)
+}
+
+/// Reads the table after the header, holding back the last `TRAILER_SIZE`
+/// bytes of the input, which are the trailer, and keeping a running length
+/// and SHA-1 of the rest.  This lets the table be decoded as it streams in,
+/// without knowing its length up front.
+struct TableReader<R> {
+    inner: R,
+    held: Vec<u8>,
+    len: u64,
+    sha1: Sha1,
+}
+
+impl<R: Read> TableReader<R> {
+    fn new(inner: R) -> TableReader<R> {
+        TableReader {
+            inner: inner,
+            held: Vec::new(),
+            len: 0,
+            sha1: Sha1::new(),
+        }
+    }
+
+    /// Skips whatever's left of the table, and checks the trailer.
+    fn finish(mut self) -> io::Result<u64> {
+        io::copy(&mut self, &mut io::sink())?;
+        check_trailer(&self.held, self.len, &self.sha1)?;
+        Ok(self.len)
+    }
+}
+
+impl<R: Read> Read for TableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.held.len() <= TRAILER_SIZE {
+            let mut chunk = [0u8; 8192];
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.held.extend_from_slice(&chunk[..n]);
+        }
+
+        let n = min(buf.len(), self.held.len() - TRAILER_SIZE);
+        buf[..n].copy_from_slice(&self.held[..n]);
+        self.sha1.update(&buf[..n]);
+        self.len += n as u64;
+        self.held.drain(..n);
+
+        Ok(n)
+    }
+}
+
+/// Checks that `offsets` holds each position in the data exactly once, as any
+/// suffix array must.
+fn check_permutation(offsets: &Offsets) -> io::Result<()> {
+    let len = offsets.len();
+    let mut seen = vec![0u64; len.div_ceil(64)];
+
+    for i in 0..len {
+        let offset = offsets.get(i);
+        if offset >= len || seen[offset / 64] & (1 << (offset % 64)) != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Index table is not a permutation"));
+        }
+        seen[offset / 64] |= 1 << (offset % 64);
+    }
+
+    Ok(())
+}
+
+pub fn write_offsets<W: Write>(offsets: &Offsets, digest: &[u8; 20], writer: W) -> io::Result<()> {
+    let mut w = BufWriter::new(writer);
+
+    Header {
+        version: FORMAT_VERSION,
+        offset_width: offsets.width() as u8,
+        flags: 0,
+        data_len: offsets.len() as u64,
+        digest: *digest,
+    }.write_to(&mut w)?;
+
+    let mut t = Checksummed::new(&mut w);
+
+    match *offsets {
+        Offsets::Narrow(ref offsets) => {
+            for offset in offsets {
+                t.write_u32::<LittleEndian>(*offset)?;
+            }
+        }
+        Offsets::Wide(ref offsets) => {
+            for offset in offsets {
+                t.write_u64::<LittleEndian>(*offset)?;
+            }
+        }
+        Offsets::Mapped(ref offsets) => {
+            for i in 0..offsets.len() {
+                if offsets.width() == 4 {
+                    t.write_u32::<LittleEndian>(offsets.get(i) as u32)?;
+                } else {
+                    t.write_u64::<LittleEndian>(offsets.get(i) as u64)?;
+                }
+            }
+        }
+    }
+
+    t.write_trailer()?;
+
+    w.flush()
+}
+
+/// Like `write_offsets`, but delta-codes the table and compresses it with
+/// zstd at the given level.  The result is typically a fraction of the size,
+/// but has to be decompressed into memory rather than mapped.
+pub fn write_compressed_offsets<W: Write>(offsets: &Offsets, digest: &[u8; 20], level: i32, writer: W) -> io::Result<()> {
+    let mut w = BufWriter::new(writer);
+
+    Header {
+        version: FORMAT_VERSION,
+        offset_width: offsets.width() as u8,
+        flags: FLAG_ZSTD | FLAG_DELTA,
+        data_len: offsets.len() as u64,
+        digest: *digest,
+    }.write_to(&mut w)?;
+
+    let mut z = BufWriter::new(zstd::stream::Encoder::new(Checksummed::new(w), level)?);
+
+    let mut prev = 0;
+    for i in 0..offsets.len() {
+        let cur = offsets.get(i) as i64;
+        write_varint(&mut z, zigzag(cur.wrapping_sub(prev)))?;
+        prev = cur;
+    }
+
+    let z = z.into_inner().map_err(|e| e.into_error())?;
+    z.finish()?.write_trailer()?.flush()
+}
+
+fn zigzag(x: i64) -> u64 {
+    ((x << 1) ^ (x >> 63)) as u64
+}
+
+fn unzigzag(x: u64) -> i64 {
+    ((x >> 1) as i64) ^ -((x & 1) as i64)
+}
+
+fn write_varint<W: Write>(mut w: W, mut x: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    w.write_all(&buf[..len])
+}
+
+fn read_varint<R: Read>(mut r: R) -> io::Result<u64> {
+    let mut x = 0u64;
+    for shift in 0..10 {
+        let byte = r.read_u8()?;
+        x |= ((byte & 0x7f) as u64) << (shift * 7);
+        if byte & 0x80 == 0 {
+            return Ok(x);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "Bad varint in index table"))
+}
+
+/// Reads the table that follows `header`, undoing whatever encoding its flags
+/// say was applied.
+fn read_table<R: Read>(r: R, header: &Header) -> io::Result<Offsets> {
+    if header.flags & FLAG_ZSTD != 0 {
+        read_entries(BufReader::new(zstd::stream::Decoder::new(r)?), header)
+    } else {
+        read_entries(r, header)
+    }
+}
+
+fn read_entries<R: Read>(mut r: R, header: &Header) -> io::Result<Offsets> {
+    let len = header.data_len as usize;
+
+    let mut prev = 0i64;
+    let mut next = || -> io::Result<u64> {
+        if header.flags & FLAG_DELTA != 0 {
+            prev = prev.wrapping_add(unzigzag(read_varint(&mut r)?));
+            Ok(prev as u64)
+        } else if header.offset_width == 4 {
+            Ok(r.read_u32::<LittleEndian>()? as u64)
+        } else {
+            r.read_u64::<LittleEndian>()
+        }
+    };
+
+    if header.offset_width == 4 {
+        let mut offsets = Vec::with_capacity(len);
+        for _ in 0..len {
+            offsets.push(next()? as u32);
+        }
+        Ok(Offsets::Narrow(offsets))
+    } else {
+        let mut offsets = Vec::with_capacity(len);
+        for _ in 0..len {
+            offsets.push(next()?);
+        }
+        Ok(Offsets::Wide(offsets))
+    }
+}
+
+/// Decodes a table whose trailer has already been checked.
+fn decode_table(table: &[u8], header: &Header) -> io::Result<Offsets> {
+    let offsets = read_table(Cursor::new(table), header)?;
+    check_permutation(&offsets)?;
+
+    Ok(offsets)
+}
+
+/// Reads the offset table of a cached index into memory, checking it fully as
+/// it's decoded.
+///
+/// Returns `None` if the entry is for different data, or in a format version
+/// we don't understand.  Returns an `UnexpectedEof` or `InvalidData` error if
+/// the entry is truncated or corrupt.
+pub fn read_offsets<R: Read>(reader: R, digest: &[u8; 20], data_len: usize) -> io::Result<Option<Offsets>> {
+    let mut r = BufReader::new(reader);
+
+    let mut buf = [0u8; HEADER_SIZE];
+    r.read_exact(&mut buf)?;
+
+    let header = Header::read(&buf)?;
+    if !header.describes(digest, data_len) {
+        return Ok(None);
+    }
+
+    let mut table = TableReader::new(r);
+    let offsets = read_table(&mut table, &header);
+
+    // A corrupt table is reported as such, whatever decoding it ran into.
+    let table_len = table.finish()?;
+    let offsets = offsets?;
+
+    if header.flags == 0 && table_len != header.data_len * header.offset_width as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad index table length"));
+    }
+
+    check_permutation(&offsets)?;
+
+    Ok(Some(offsets))
+}
+
+/// Memory-maps the offset table of a cached index, so it can be used in place
+/// without reading it.  Compressed tables are decompressed into memory instead,
+/// and checked fully.
+///
+/// With `verify`, the checksum and that the table is a permutation are checked,
+/// as `read_offsets` does.  Without it, only the header and the table's length
+/// are, so that loading doesn't touch every page of the table.  That catches
+/// entries cut short by a killed process, but not ones corrupted in place.
+///
+/// Returns `None` or an error under the same conditions as `read_offsets`.
+/// The file must not be modified while the returned offsets are alive.
+pub fn map_offsets(file: &File, digest: &[u8; 20], data_len: usize, verify: bool) -> io::Result<Option<Offsets>> {
+    let file_len = file.metadata()?.len();
+    if file_len < HEADER_SIZE as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated index header"));
+    }
+
+    let map = Mmap::open(file, Protection::Read)?;
+
+    let header = Header::read(unsafe { map.as_slice() })?;
+    if !header.describes(digest, data_len) {
+        return Ok(None);
+    }
+
+    if header.flags != 0 {
+        let table = split_trailer(unsafe { &map.as_slice()[HEADER_SIZE..] }, true)?;
+        return Ok(Some(decode_table(table, &header)?));
+    }
+
+    let table = split_trailer(unsafe { &map.as_slice()[HEADER_SIZE..] }, verify)?;
+
+    let width = header.offset_width as usize;
+    if table.len() != data_len * width {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad index table length"));
+    }
+
+    let offsets = Offsets::Mapped(MappedOffsets::new(map, HEADER_SIZE, width, data_len));
+    if verify {
+        check_permutation(&offsets)?;
+    }
+
+    Ok(Some(offsets))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Cursor, Seek, SeekFrom};
+
+    use super::*;
+    use suffix_array;
+
+    fn to_vec(offsets: &Offsets) -> Vec<usize> {
+        (0..offsets.len()).map(|i| offsets.get(i)).collect()
+    }
+
+    fn read_error(buf: &[u8], data_len: usize) -> io::ErrorKind {
+        match read_offsets(Cursor::new(buf), &[1u8; 20], data_len) {
+            Ok(_) => panic!("corrupt entry was accepted"),
+            Err(e) => e.kind(),
+        }
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = Header {
+            version: FORMAT_VERSION,
+            offset_width: 8,
+            flags: FLAG_ZSTD,
+            data_len: 0x123456789,
+            digest: [7u8; 20],
+        };
+
+        let mut buf = Vec::new();
+        header.write_to(&mut buf).unwrap();
+
+        assert_eq!(buf.len(), HEADER_SIZE);
+        assert_eq!(header, Header::read(&buf).unwrap());
+    }
+
+    #[test]
+    fn test_read_roundtrip() {
+        let data = b"this is a test 12345678 test";
+        let offsets = suffix_array::build(data);
+
+        let mut buf = Vec::new();
+        write_offsets(&offsets, &[1u8; 20], &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_SIZE + 4 * data.len() + TRAILER_SIZE);
+
+        let loaded = read_offsets(Cursor::new(&buf), &[1u8; 20], data.len()).unwrap().unwrap();
+        assert_eq!(to_vec(&offsets), to_vec(&loaded));
+
+        assert!(read_offsets(Cursor::new(&buf), &[2u8; 20], data.len()).unwrap().is_none());
+        assert!(read_offsets(Cursor::new(&buf), &[1u8; 20], data.len() + 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        let mut data = b"this is a test 12345678 test".to_vec();
+        data.extend_from_slice(&[0u8; 4096]);
+        let offsets = suffix_array::build(&data);
+
+        let mut buf = Vec::new();
+        write_compressed_offsets(&offsets, &[1u8; 20], 3, &mut buf).unwrap();
+        assert!(buf.len() < HEADER_SIZE + data.len() / 4);
+
+        let loaded = read_offsets(Cursor::new(&buf), &[1u8; 20], data.len()).unwrap().unwrap();
+        assert_eq!(to_vec(&offsets), to_vec(&loaded));
+    }
+
+    #[test]
+    fn test_varint_roundtrip() {
+        for &x in &[0i64, 1, -1, 63, -64, 64, 1 << 32, -(1 << 40), i64::max_value(), i64::min_value()] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, zigzag(x)).unwrap();
+            assert_eq!(x, unzigzag(read_varint(Cursor::new(&buf)).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_corrupt_entries_are_rejected() {
+        let data = b"this is a test 12345678 test";
+        let offsets = suffix_array::build(data);
+
+        for &compressed in &[false, true] {
+            let mut buf = Vec::new();
+            if compressed {
+                write_compressed_offsets(&offsets, &[1u8; 20], 3, &mut buf).unwrap();
+            } else {
+                write_offsets(&offsets, &[1u8; 20], &mut buf).unwrap();
+            }
+
+            for &len in &[HEADER_SIZE - 1, HEADER_SIZE, HEADER_SIZE + 5, buf.len() - 1] {
+                assert_eq!(read_error(&buf[..len], data.len()), io::ErrorKind::UnexpectedEof);
+            }
+
+            let mut flipped = buf.clone();
+            flipped[HEADER_SIZE + 1] ^= 0x40;
+            assert_eq!(read_error(&flipped, data.len()), io::ErrorKind::InvalidData);
+        }
+
+        // A well-formed entry that isn't a suffix array of anything.
+        let mut buf = Vec::new();
+        write_offsets(&Offsets::Narrow(vec![0, 2, 2]), &[1u8; 20], &mut buf).unwrap();
+        assert_eq!(read_error(&buf, 3), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_map_roundtrip() {
+        let data = b"this is really a cool uftu 12345678 uftu";
+        let offsets = suffix_array::build(data);
+
+        let path = env::temp_dir().join("rsdiff-test-map-roundtrip.idx");
+        let mut file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+
+        write_offsets(&offsets, &[3u8; 20], &mut file).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        for &verify in &[false, true] {
+            let mapped = map_offsets(&file, &[3u8; 20], data.len(), verify).unwrap().unwrap();
+            assert_eq!(mapped.width(), 4);
+            assert_eq!(to_vec(&offsets), to_vec(&mapped));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn map_error(file: &File, data_len: usize, verify: bool) -> Option<io::ErrorKind> {
+        match map_offsets(file, &[3u8; 20], data_len, verify) {
+            Ok(_) => None,
+            Err(e) => Some(e.kind()),
+        }
+    }
+
+    #[test]
+    fn test_map_checks() {
+        let data = b"this is really a cool uftu 12345678 uftu";
+        let offsets = suffix_array::build(data);
+
+        let mut buf = Vec::new();
+        write_offsets(&offsets, &[3u8; 20], &mut buf).unwrap();
+
+        let path = env::temp_dir().join("rsdiff-test-map-checks.idx");
+        let mut file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+
+        // A torn write is caught even without verifying.
+        file.write_all(&buf[..buf.len() - 1]).unwrap();
+        assert_eq!(map_error(&file, data.len(), false), Some(io::ErrorKind::UnexpectedEof));
+        assert_eq!(map_error(&file, data.len(), true), Some(io::ErrorKind::UnexpectedEof));
+
+        // Corruption in place, including an offset past the end of the data.
+        for &(at, value) in &[(HEADER_SIZE + 1, 0x40), (HEADER_SIZE, 0xff)] {
+            let mut corrupt = buf.clone();
+            corrupt[at] ^= value;
+            file.set_len(0).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.write_all(&corrupt).unwrap();
+            assert_eq!(map_error(&file, data.len(), true), Some(io::ErrorKind::InvalidData));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,3 +1,5 @@
+use std::cmp::min;
+use std::collections::VecDeque;
 use std::io::{Read, Write, Seek};
 use std::io;
 
@@ -5,17 +7,25 @@ use zstd;
 use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt, ByteOrder};
 
 use diff::{
-    Index,
+    DiffOptions,
+    Match,
+    MatchStrategy,
     write_delta,
     write_zeros,
-    MatchIter,
 };
 
+use matcher::repeats::{RepeatFinder, SELF_COPY_WINDOW};
 use patch::{
     read_paired_bufs,
     read_size_from,
 };
 
+/// Set in a command's `old_offset` to copy from the new data written so far,
+/// rather than from the old data.  The rest of `old_offset` is where in the
+/// new data to copy from, and `bytewise_add_size` bytes are copied exactly:
+/// no delta bytes follow the command, only the extra data.
+pub const SELF_COPY: u64 = 1 << 63;
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Command {
     pub old_offset: u64,
@@ -61,14 +71,14 @@ impl Command {
     }
 }
 
-pub fn generate_full_patch<PatchW: Write>(old: &Index, new: &[u8], mut patch: PatchW) -> io::Result<()> {
+pub fn generate_full_patch<S: MatchStrategy, PatchW: Write>(strategy: &S, new: &[u8], options: &DiffOptions, mut patch: PatchW) -> io::Result<()> {
     // let mut patch = zstd::stream::Encoder::new(patch, 19).unwrap();
 
     let mut i = 0;
 
     let mut k = 0;
 
-    for m in MatchIter::from(old, new) {
+    for m in strategy.matches(new, options) {
 
         if k % 1024 == 0 {
             println!("{} / {} ({}%)", i, new.len(), i * 100 / new.len());
@@ -76,42 +86,222 @@ pub fn generate_full_patch<PatchW: Write>(old: &Index, new: &[u8], mut patch: Pa
 
         k += 1;
 
-        let mm = m.matched;
+        write_match(&mut patch, strategy.old(), &new[i..], &m)?;
+
+        i += m.matched.len() + m.unmatched_suffix;
+    }
+
+    // patch.finish();
+
+    Ok(())
+}
+
+/// Like `generate_full_patch`, but reads the new data from `new` a window of
+/// `window` bytes at a time, writing each window's commands before reading the
+/// next one.  Only the old data needs to be kept in memory.
+///
+/// Matches can't span windows.  So that one starting near the end of a window
+/// isn't cut short, the unmatched bytes at the end of each window (up to half
+/// a window of them) are carried over to the start of the next.
+pub fn generate_streaming_patch<S, NewR, PatchW>(strategy: &S, mut new: NewR, window: usize, options: &DiffOptions, mut patch: PatchW) -> io::Result<()>
+    where S: MatchStrategy, NewR: Read, PatchW: Write
+{
+    assert!(window > 0, "window must be positive");
+
+    let mut buf = Vec::with_capacity(window);
+    let mut streamed = 0;
+
+    loop {
+        let wanted = window - buf.len();
+        let eof = (&mut new).take(wanted as u64).read_to_end(&mut buf)? < wanted;
 
-        let cmd = Command {
-            old_offset: mm.old_offset as u64,
-            bytewise_add_size: mm.len() as u64,
-            extra_append_size: m.unmatched_suffix as u64,
-        };
+        let mut matches = strategy.matches(&buf, &DiffOptions {
+            new_offset: options.new_offset + streamed,
+            ..options.clone()
+        }).collect::<Vec<_>>();
 
-        println!("write cmd: {:?}", cmd);
+        if !eof {
+            if let Some(last) = matches.last_mut() {
+                last.unmatched_suffix -= min(last.unmatched_suffix, window / 2);
+            }
+        }
+
+        let mut i = 0;
+        for m in &matches {
+            if m.matched.len() > 0 || m.unmatched_suffix > 0 {
+                write_match(&mut patch, strategy.old(), &buf[i..], m)?;
+            }
+            i += m.matched.len() + m.unmatched_suffix;
+        }
+
+        buf.drain(..i);
+        streamed += i;
 
-        cmd.write_to(&mut patch)?;
+        println!("{} bytes streamed", streamed);
 
-        write_delta(
-            &mut patch,
-            &old.data[mm.old_offset .. mm.old_offset + mm.len()],
-            &new[i .. i + mm.len()])?;
+        if eof {
+            return Ok(());
+        }
+    }
+}
+
+/// Like `generate_full_patch`, but repeats within the extra data of something
+/// earlier in the new data become self-copies (see `SELF_COPY`), instead of
+/// being written out again.
+pub fn generate_patch_with_repeats<S: MatchStrategy, PatchW: Write>(strategy: &S, new: &[u8], options: &DiffOptions, mut patch: PatchW) -> io::Result<()> {
+    let mut repeats = RepeatFinder::new(new);
+
+    let mut i = 0;
 
-        let extra_begin = i + mm.len();
+    for m in strategy.matches(new, options) {
+        let extra_begin = i + m.matched.len();
         let extra_end = extra_begin + m.unmatched_suffix;
 
-        patch.write_all(&new[extra_begin .. extra_end]);
+        let found = repeats.find(extra_begin..extra_end);
+
+        // The match itself takes the extra data up to the first repeat, and
+        // each repeat the extra data up to the next.
+        let first = found.first().map_or(extra_end, |r| r.target);
+        if m.matched.len() > 0 || first > extra_begin {
+            write_match(&mut patch, strategy.old(), &new[i..], &Match {
+                matched: m.matched,
+                unmatched_suffix: first - extra_begin,
+            })?;
+        }
+
+        for (k, r) in found.iter().enumerate() {
+            let end = found.get(k + 1).map_or(extra_end, |next| next.target);
+
+            let cmd = Command {
+                old_offset: SELF_COPY | r.source as u64,
+                bytewise_add_size: r.len as u64,
+                extra_append_size: (end - r.target - r.len) as u64,
+            };
+
+            println!("write cmd: {:?}", cmd);
+
+            cmd.write_to(&mut patch)?;
+            patch.write_all(&new[r.target + r.len .. end])?;
+        }
 
         i = extra_end;
     }
 
-    // patch.finish();
-
     Ok(())
 }
 
-pub fn apply_patch<PatchR: Read, OldRS: Read+Seek, NewW: Write>(mut patch: PatchR, mut old: OldRS, mut new: NewW)
+/// Writes the command for a match, followed by its delta and extra data.
+/// `new` starts where the match does.
+fn write_match<W: Write>(mut patch: W, old: &[u8], new: &[u8], m: &Match) -> io::Result<()> {
+    let mm = &m.matched;
+
+    let cmd = Command {
+        old_offset: mm.old_offset as u64,
+        bytewise_add_size: mm.len() as u64,
+        extra_append_size: m.unmatched_suffix as u64,
+    };
+
+    println!("write cmd: {:?}", cmd);
+
+    cmd.write_to(&mut patch)?;
+
+    write_delta(
+        &mut patch,
+        &old[mm.old_offset .. mm.old_offset + mm.len()],
+        &new[.. mm.len()])?;
+
+    patch.write_all(&new[mm.len() .. mm.len() + m.unmatched_suffix])
+}
+
+/// Passes writes through, keeping the last `SELF_COPY_WINDOW` bytes for
+/// self-copies.
+struct History<W> {
+    inner: W,
+    recent: VecDeque<u8>,
+    written: u64,
+
+    /// How many bytes to keep; `SELF_COPY_WINDOW` outside tests.
+    window: usize,
+}
+
+impl<W: Write> History<W> {
+    fn new(inner: W) -> History<W> {
+        History::with_window(inner, SELF_COPY_WINDOW as usize)
+    }
+
+    fn with_window(inner: W, window: usize) -> History<W> {
+        History {
+            inner: inner,
+            recent: VecDeque::new(),
+            written: 0,
+            window: window,
+        }
+    }
+
+    /// Where `recent` starts in what's been written.
+    fn start(&self) -> u64 {
+        self.written - self.recent.len() as u64
+    }
+
+    /// Writes `len` bytes copied from `source` in what's been written so far.
+    /// The source can overlap what this writes.
+    fn copy(&mut self, source: u64, len: u64) -> io::Result<()> {
+        if source < self.start() || source >= self.written {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "self-copy source out of range"));
+        }
+
+        let mut copied = 0;
+        while copied < len {
+            // Only what's already been written can be copied.  Each write
+            // can push the start of the window forward.
+            let from = (source + copied - self.start()) as usize;
+            let size = min(len - copied, self.written - (source + copied)) as usize;
+
+            let buf = self.recent.range(from..from + size).cloned().collect::<Vec<_>>();
+            self.write_all(&buf)?;
+
+            copied += size as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for History<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+
+        self.recent.extend(&buf[..size]);
+        let excess = self.recent.len().saturating_sub(self.window);
+        self.recent.drain(..excess);
+        self.written += size as u64;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn apply_patch<PatchR: Read, OldRS: Read+Seek, NewW: Write>(mut patch: PatchR, mut old: OldRS, new: NewW)
  -> io::Result<()>
 {
     // let mut patch = zstd::Decoder::new(patch).unwrap();
 
+    let mut new = History::new(new);
+
     while let Some(cmd) = Command::read_from(&mut patch)? {
+        if cmd.old_offset & SELF_COPY != 0 {
+            new.copy(cmd.old_offset & !SELF_COPY, cmd.bytewise_add_size)?;
+
+            read_size_from(cmd.extra_append_size, &mut patch, |e| {
+                new.write_all(&e)
+            })?;
+
+            continue;
+        }
+
         old.seek(io::SeekFrom::Start(cmd.old_offset))?;
 
         read_paired_bufs(cmd.bytewise_add_size, &mut old, &mut patch, |o, d| {
@@ -137,7 +327,9 @@ pub fn print_patch<PatchR: Read>(mut patch: PatchR)
     while let Some(cmd) = Command::read_from(&mut patch)? {
         println!("read {:?}", cmd);
 
-        read_size_from(cmd.bytewise_add_size, &mut patch, |_| {Ok(())})?;
+        if cmd.old_offset & SELF_COPY == 0 {
+            read_size_from(cmd.bytewise_add_size, &mut patch, |_| {Ok(())})?;
+        }
         read_size_from(cmd.extra_append_size, &mut patch, |_| {Ok(())})?;
     }
 
@@ -152,12 +344,13 @@ mod tests {
 
     use super::*;
     use diff::Index;
+    use test_util::{OLD, NEW};
 
     fn assert_roundtrip(old: &[u8], new: &[u8]) {
         let index = Index::compute(old.to_vec());
 
         let mut patch = Vec::new();
-        generate_full_patch(&index, &new[..], &mut patch).unwrap();
+        generate_full_patch(&index, &new[..], &Default::default(), &mut patch).unwrap();
         
         print_patch(Cursor::new(&patch));
         
@@ -183,4 +376,73 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_streaming_patches() {
+        let index = Index::compute(OLD.to_vec());
+        let options = Default::default();
+
+        for &window in &[1, 3, 16, 40, 1000] {
+            let mut patch = Vec::new();
+            generate_streaming_patch(&index, Cursor::new(NEW), window, &options, &mut patch).unwrap();
+
+            let mut computed = Vec::new();
+            apply_patch(Cursor::new(patch), Cursor::new(OLD), &mut computed).unwrap();
+            assert_eq!(NEW, &computed[..], "window: {}", window);
+        }
+
+        // With everything in one window, it's the same as the full patch.
+        let mut full = Vec::new();
+        generate_full_patch(&index, NEW, &options, &mut full).unwrap();
+        let mut streamed = Vec::new();
+        generate_streaming_patch(&index, Cursor::new(NEW), NEW.len() + 1, &options, &mut streamed).unwrap();
+        assert_eq!(full, streamed);
+    }
+
+    #[test]
+    fn test_patch_with_repeats() {
+
+        let table = b"a table that isn't in the old file, long enough to be worth copying";
+        let mut new = b"this is a test ".to_vec();
+        new.extend_from_slice(table);
+        new.extend_from_slice(b" 12345678 test, ");
+        new.extend_from_slice(table);
+        new.extend_from_slice(&[b'z'; 100]);
+        new.extend_from_slice(b"with a little more text on the end");
+
+        let index = Index::compute(OLD.to_vec());
+        let options = Default::default();
+
+        let mut plain = Vec::new();
+        generate_full_patch(&index, &new, &options, &mut plain).unwrap();
+
+        let mut patch = Vec::new();
+        generate_patch_with_repeats(&index, &new, &options, &mut patch).unwrap();
+
+        // The second copy of the table, and most of the run of z's, are
+        // each a single command.
+        assert!(patch.len() + table.len() < plain.len(), "{} vs {}", patch.len(), plain.len());
+
+        let mut computed = Vec::new();
+        apply_patch(Cursor::new(&patch), Cursor::new(OLD), &mut computed).unwrap();
+        assert_eq!(new, computed);
+    }
+
+    #[test]
+    fn test_overlapping_copy_past_window() {
+        let mut history = History::with_window(Vec::new(), 16);
+        history.write_all(b"0123456789abcdefghijklmnopqrstuvwxyz").unwrap();
+
+        // Runs of the last byte, and of the last three, each written in
+        // several pieces as the window slides along.
+        history.copy(35, 5).unwrap();
+        history.copy(38, 10).unwrap();
+        assert_eq!(&history.inner[36..], b"zzzzzzzzzzzzzzz");
+
+        history.write_all(b"xyz").unwrap();
+        history.copy(51, 10).unwrap();
+        assert_eq!(&history.inner[51..], b"xyzxyzxyzxyzx");
+
+        assert_eq!(history.copy(history.written - 17, 1).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
 }
@@ -1,2 +1,3 @@
 pub mod bsdiff;
+pub mod index;
 pub mod linear_diff;
\ No newline at end of file
@@ -0,0 +1,152 @@
+use std::cmp::min;
+use std::ops::Range;
+use std::u32;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use diff::{MatchIndex, longest_prefix};
+
+/// Positions are hashed by this many leading bytes, so shorter matches
+/// aren't found.
+pub const HASH_LEN: usize = 4;
+
+const HASH_BITS: usize = 20;
+
+/// Marks the end of a chain.
+const NONE: u32 = u32::MAX;
+
+/// Stop walking a chain once a match this long is found.
+const GOOD_ENOUGH: usize = 4096;
+
+/// An LZ77-style hash chain over the data: for each position, the previous
+/// position whose first `HASH_LEN` bytes hash the same.
+///
+/// Building it is a single linear pass, much faster than sorting suffixes for
+/// an `Index`.  Lookups walk a chain of at most `depth` candidates, so they
+/// can miss the longest match, and patches come out somewhat larger.
+///
+/// Only the first 4 GiB of the data is indexed.
+pub struct HashChainIndex {
+    pub data: Vec<u8>,
+    depth: usize,
+    head: Vec<u32>,
+    chain: Vec<u32>,
+}
+
+/// Hashes the first `HASH_LEN` bytes to `HASH_BITS` bits.
+pub fn hash(bytes: &[u8]) -> usize {
+    (LittleEndian::read_u32(bytes).wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+impl HashChainIndex {
+    /// Builds an index for the given compression level, from 1 (fastest) to
+    /// 9 (smallest patches), which sets how many candidates each lookup
+    /// tries: 1 at level 1, doubling up to 256 at level 9.
+    pub fn compute(data: Vec<u8>, level: u32) -> HashChainIndex {
+        assert!(level >= 1 && level <= 9, "level must be between 1 and 9");
+
+        println!("Hashing");
+
+        let indexed = min(data.len(), u32::MAX as usize);
+
+        let mut head = vec![NONE; 1 << HASH_BITS];
+        let mut chain = vec![NONE; indexed];
+
+        for pos in 0..indexed.saturating_sub(HASH_LEN - 1) {
+            let h = hash(&data[pos..]);
+            chain[pos] = head[h];
+            head[h] = pos as u32;
+        }
+
+        HashChainIndex {
+            data: data,
+            depth: 1 << (level - 1),
+            head: head,
+            chain: chain,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+impl MatchIndex for HashChainIndex {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+        if buf.len() < HASH_LEN {
+            return 0..0;
+        }
+
+        let mut best = 0..0;
+
+        let mut pos = self.head[hash(buf)];
+        for _ in 0..self.depth {
+            if pos == NONE {
+                break;
+            }
+
+            let start = pos as usize;
+            let len = longest_prefix(&self.data[start..], buf);
+            if len > best.len() {
+                best = start..start + len;
+                if len >= min(GOOD_ENOUGH, buf.len()) {
+                    break;
+                }
+            }
+
+            pos = self.chain[start];
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{assert_roundtrip, pseudo_random, OLD, NEW};
+
+    #[test]
+    fn test_hash_chain_longest_match() {
+        let data = b"the quick brown fox jumps over the lazy dog; the quick brown cat".to_vec();
+
+        let index = HashChainIndex::compute(data.clone(), 9);
+        assert_eq!(&data[index.longest_match(b"the quick brown fox!")], b"the quick brown fox");
+        assert_eq!(&data[index.longest_match(b"the lazy cat")], b"the lazy ");
+        assert_eq!(index.longest_match(b"zzzz").len(), 0);
+        assert_eq!(index.longest_match(b"the").len(), 0);
+
+        // Level 1 only looks at the latest position with the same hash.
+        let index = HashChainIndex::compute(data.clone(), 1);
+        assert_eq!(&data[index.longest_match(b"the quick brown fox!")], b"the quick brown ");
+    }
+
+    #[test]
+    fn test_hash_chain_stops_at_good_enough_match() {
+        let block = pseudo_random(6000, 3);
+
+        // The latest copy of the block is cut short, but still long enough
+        // that the older, complete copy isn't looked at.
+        let mut data = block.clone();
+        data.extend_from_slice(&block[..GOOD_ENOUGH + 10]);
+        data.extend_from_slice(b"the end");
+
+        let index = HashChainIndex::compute(data, 9);
+        assert_eq!(index.longest_match(&block), 6000..6000 + GOOD_ENOUGH + 10);
+
+        // A match to the end of a short buffer is good enough too.
+        assert_eq!(index.longest_match(&block[..100]), 6000..6100);
+    }
+
+    #[test]
+    fn test_hash_chain_patch_roundtrip() {
+        for level in 1..10 {
+            let index = HashChainIndex::compute(OLD.to_vec(), level);
+            assert_roundtrip(&index, NEW, &Default::default());
+        }
+    }
+}
@@ -1,5 +1,6 @@
 extern crate byteorder;
 extern crate bzip2;
+extern crate memmap;
 extern crate zstd;
 extern crate sha1;
 
@@ -7,3 +8,11 @@ pub mod format;
 
 pub mod patch;
 pub mod diff;
+pub mod matcher;
+pub mod sparse;
+pub mod hash_chain;
+
+mod suffix_array;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,273 @@
+use std::cmp::min;
+use std::ops::Range;
+
+use diff::{Index, Delta, DiffOptions, Match, MatchIndex, MatchStrategy, longest_prefix};
+
+/// A `MatchStrategy` that uses `Bsdiff43`.  bsdiff's thresholds are fixed,
+/// so `DiffOptions` are ignored.
+pub struct Bsdiff43Strategy<'a> {
+    pub index: &'a Index,
+}
+
+impl<'a> MatchStrategy for Bsdiff43Strategy<'a> {
+    fn old(&self) -> &[u8] {
+        &self.index.data
+    }
+
+    fn matches<'b>(&'b self, new: &'b [u8], _options: &DiffOptions) -> Box<dyn Iterator<Item=Match> + 'b> {
+        Box::new(Bsdiff43::from(self.index, new))
+    }
+
+    fn reference_range(&self, offset: usize) -> Range<usize> {
+        self.index.reference_bounds(offset).1
+    }
+}
+
+/// Colin Percival's match selection from bsdiff 4.3, reproduced step for step
+/// so that `format::bsdiff::generate_bsdiff43_patch` emits the same commands
+/// as the reference tool.
+///
+/// Unlike `MatchIter`, this doesn't look for exact matches and then extend
+/// them: it scans forward until the longest match found at the current
+/// position beats the bytes that would already match by carrying on from the
+/// previous match (the "old score") by more than 8 bytes.  Each `Match` is a
+/// single delta run (`lower_delta_len`), extended forwards from the previous
+/// match and backwards from the next one as long as at least half the bytes
+/// agree.
+///
+/// The index's data is treated as one file: reference boundaries are ignored,
+/// just as bsdiff would ignore them in a concatenated file.
+pub struct Bsdiff43<'a> {
+    old: &'a Index,
+    new: &'a [u8],
+    scan: usize,
+    len: usize,
+    pos: usize,
+    last_scan: usize,
+    last_pos: usize,
+    last_offset: isize,
+}
+
+impl<'a> Bsdiff43<'a> {
+    pub fn from(old: &'a Index, new: &'a [u8]) -> Bsdiff43<'a> {
+        Bsdiff43 {
+            old: old,
+            new: new,
+            scan: 0,
+            len: 0,
+            pos: 0,
+            last_scan: 0,
+            last_pos: 0,
+            last_offset: 0,
+        }
+    }
+
+    /// Where bsdiff leaves the read position in the old file after the last
+    /// command.  This is only meaningful once the iterator is exhausted, and
+    /// is needed to reproduce the seek of the final command.
+    pub fn final_old_offset(&self) -> usize {
+        self.last_pos
+    }
+
+    /// bsdiff's `search`: a binary search over the suffix array, including
+    /// the empty suffix at rank 0, that narrows down to two neighbouring
+    /// suffixes and returns the longer of their matches (the later one on a
+    /// tie).  Returns `(position, length)`.
+    fn search(&self, buf: &[u8]) -> (usize, usize) {
+        let old = &self.old.data;
+        let suffix = |rank: usize| if rank == 0 { old.len() } else { self.old.suffix_at(rank - 1) };
+
+        let (mut st, mut en) = (0, old.len());
+        while en - st >= 2 {
+            let x = st + (en - st) / 2;
+            let p = suffix(x);
+            let n = min(old.len() - p, buf.len());
+            if &old[p..p + n] < &buf[..n] {
+                st = x;
+            } else {
+                en = x;
+            }
+        }
+
+        let (a, b) = (suffix(st), suffix(en));
+        let x = longest_prefix(&old[a..], buf);
+        let y = longest_prefix(&old[b..], buf);
+
+        if x > y { (a, x) } else { (b, y) }
+    }
+
+    /// Whether `new[i]` matches the byte at the same offset from the previous
+    /// match in the old file.
+    fn matches_last_offset(&self, i: usize) -> bool {
+        let j = i as isize + self.last_offset;
+        j >= 0 && (j as usize) < self.old.data.len() && self.old.data[j as usize] == self.new[i]
+    }
+}
+
+impl<'a> Iterator for Bsdiff43<'a> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        let old = &self.old.data;
+        let new = self.new;
+
+        while self.scan < new.len() {
+            let mut old_score = 0isize;
+
+            self.scan += self.len;
+            let mut scsc = self.scan;
+
+            while self.scan < new.len() {
+                let (pos, len) = self.search(&new[self.scan..]);
+                self.pos = pos;
+                self.len = len;
+
+                while scsc < self.scan + len {
+                    if self.matches_last_offset(scsc) {
+                        old_score += 1;
+                    }
+                    scsc += 1;
+                }
+
+                if (len as isize == old_score && len != 0) || len as isize > old_score + 8 {
+                    break;
+                }
+
+                if self.matches_last_offset(self.scan) {
+                    old_score -= 1;
+                }
+
+                self.scan += 1;
+            }
+
+            if self.len as isize == old_score && self.scan != new.len() {
+                continue;
+            }
+
+            // Extend forwards from the previous match while at least half
+            // the bytes agree.
+            let mut lenf = 0;
+            {
+                let (mut s, mut best) = (0isize, 0isize);
+                let mut i = 0;
+                while self.last_scan + i < self.scan && self.last_pos + i < old.len() {
+                    if old[self.last_pos + i] == new[self.last_scan + i] {
+                        s += 1;
+                    }
+                    i += 1;
+                    if s * 2 - i as isize > best * 2 - lenf as isize {
+                        best = s;
+                        lenf = i;
+                    }
+                }
+            }
+
+            // And backwards from this one.
+            let mut lenb = 0;
+            if self.scan < new.len() {
+                let (mut s, mut best) = (0isize, 0isize);
+                let mut i = 1;
+                while self.scan >= self.last_scan + i && self.pos >= i {
+                    if old[self.pos - i] == new[self.scan - i] {
+                        s += 1;
+                    }
+                    if s * 2 - i as isize > best * 2 - lenb as isize {
+                        best = s;
+                        lenb = i;
+                    }
+                    i += 1;
+                }
+            }
+
+            // If the extensions overlap, split the overlap where it scores
+            // best.
+            if self.last_scan + lenf > self.scan - lenb {
+                let overlap = (self.last_scan + lenf) - (self.scan - lenb);
+                let (mut s, mut best, mut lens) = (0isize, 0isize, 0);
+                for i in 0..overlap {
+                    if new[self.last_scan + lenf - overlap + i] == old[self.last_pos + lenf - overlap + i] {
+                        s += 1;
+                    }
+                    if new[self.scan - lenb + i] == old[self.pos - lenb + i] {
+                        s -= 1;
+                    }
+                    if s > best {
+                        best = s;
+                        lens = i + 1;
+                    }
+                }
+
+                lenf = lenf + lens - overlap;
+                lenb -= lens;
+            }
+
+            let m = Match {
+                matched: Delta {
+                    reference: if lenf > 0 { self.old.reference_at(self.last_pos) } else { 0 },
+                    old_offset: self.last_pos,
+                    lower_delta_len: lenf,
+                    mid_exact_len: 0,
+                    upper_delta_len: 0,
+                },
+                unmatched_suffix: (self.scan - lenb) - (self.last_scan + lenf),
+            };
+
+            self.last_scan = self.scan - lenb;
+            self.last_pos = self.pos - lenb;
+            self.last_offset = self.pos as isize - self.scan as isize;
+
+            return Some(m);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{assert_roundtrip, OLD, NEW};
+
+    #[test]
+    fn test_identical_input() {
+        let data = b"abcdefghijklmnop".to_vec();
+        let index = Index::compute(data.clone());
+
+        let mut matcher = Bsdiff43::from(&index, &data);
+        let matches = matcher.by_ref().collect::<Vec<_>>();
+
+        assert_eq!(matches, vec![Match {
+            matched: Delta {
+                reference: 0,
+                old_offset: 0,
+                lower_delta_len: 16,
+                mid_exact_len: 0,
+                upper_delta_len: 0,
+            },
+            unmatched_suffix: 0,
+        }]);
+
+        // bsdiff's final seek goes back to where the last search matched.
+        assert_eq!(matcher.final_old_offset(), 0);
+    }
+
+    #[test]
+    fn test_matches_cover_new() {
+        let index = Index::compute(OLD.to_vec());
+
+        let mut covered = 0;
+        for m in Bsdiff43::from(&index, NEW) {
+            let d = &m.matched;
+            assert_eq!(d.mid_exact_len + d.upper_delta_len, 0);
+            assert!(d.old_offset + d.len() <= OLD.len());
+            covered += d.len() + m.unmatched_suffix;
+        }
+        assert_eq!(covered, NEW.len());
+    }
+
+    #[test]
+    fn test_strategy_patch_roundtrip() {
+        let index = Index::compute(OLD.to_vec());
+        assert_roundtrip(&Bsdiff43Strategy { index: &index }, NEW, &Default::default());
+    }
+}
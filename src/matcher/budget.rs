@@ -0,0 +1,363 @@
+use std::cell::RefCell;
+use std::cmp::min;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use diff::{DiffOptions, Match, MatchStrategy, SearchLimit};
+
+/// How much a stage of `Budgeted` may spend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    /// Wall-clock time from when the stage starts.
+    Time(Duration),
+
+    /// Number of index searches.  Unlike a time budget, this gives the same
+    /// patch on every run.
+    Searches(usize),
+
+    Unlimited,
+}
+
+impl Budget {
+    fn limit(&self) -> Option<SearchLimit> {
+        match *self {
+            Budget::Time(time) => Some(SearchLimit::time(time)),
+            Budget::Searches(searches) => Some(SearchLimit::searches(searches)),
+            Budget::Unlimited => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Primary,
+    Fallback,
+
+    /// Whatever's left is copied into the patch as extra data, as
+    /// `generate_idempotent_patch` does for a whole file.
+    Literal,
+}
+
+/// Which stage produced the matches for part of the new data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageReport {
+    pub stage: Stage,
+
+    /// The part of the new data this stage covered.
+    pub range: Range<usize>,
+
+    pub elapsed: Duration,
+}
+
+/// Wraps a strategy so that patch generation finishes within a budget.
+///
+/// Matches come from `primary` until its budget runs out, then from
+/// `fallback` (typically something cheap like a `HashChainIndex`) for the
+/// rest of the new data until its budget runs out too, and then the rest is
+/// left as extra data.
+///
+/// Each stage's budget is passed on to it as a `DiffOptions::limit`, which
+/// `MatchIter` (and so any `MatchIndex`, or a `Chunked` one) checks before
+/// every search.  The stage's matches are used up to where it stopped
+/// searching, and the next stage carries on from there.  Strategies that
+/// don't check the limit run to the end.
+///
+/// Both strategies must find matches in the same old data.  After generating
+/// a patch, `report()` says which stage covered which part of it.
+pub struct Budgeted<'a> {
+    pub primary: &'a dyn MatchStrategy,
+    pub primary_budget: Budget,
+
+    pub fallback: Option<&'a dyn MatchStrategy>,
+    pub fallback_budget: Budget,
+
+    report: RefCell<Vec<StageReport>>,
+}
+
+impl<'a> Budgeted<'a> {
+    pub fn new(primary: &'a dyn MatchStrategy, budget: Budget) -> Budgeted<'a> {
+        Budgeted {
+            primary: primary,
+            primary_budget: budget,
+            fallback: None,
+            fallback_budget: Budget::Unlimited,
+            report: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn with_fallback(mut self, fallback: &'a dyn MatchStrategy, budget: Budget) -> Budgeted<'a> {
+        assert_eq!(self.primary.old().len(), fallback.old().len(), "fallback must use the same old data");
+
+        self.fallback = Some(fallback);
+        self.fallback_budget = budget;
+        self
+    }
+
+    /// The stages used by the last `matches` call, in order.
+    pub fn report(&self) -> Vec<StageReport> {
+        self.report.borrow().clone()
+    }
+}
+
+impl<'a> MatchStrategy for Budgeted<'a> {
+    fn old(&self) -> &[u8] {
+        self.primary.old()
+    }
+
+    fn matches<'b>(&'b self, new: &'b [u8], options: &DiffOptions) -> Box<dyn Iterator<Item=Match> + 'b> {
+        *self.report.borrow_mut() = vec![StageReport {
+            stage: Stage::Primary,
+            range: 0..0,
+            elapsed: Duration::from_secs(0),
+        }];
+
+        let limit = self.primary_budget.limit();
+        let inner = self.primary.matches(new, &DiffOptions {
+            limit: limit.clone(),
+            ..options.clone()
+        });
+
+        Box::new(BudgetedIter {
+            strategy: self,
+            new: new,
+            options: options.clone(),
+            inner: Some(inner),
+            limit: limit,
+            started: Instant::now(),
+            pos: 0,
+        })
+    }
+
+    fn reference_range(&self, offset: usize) -> Range<usize> {
+        self.primary.reference_range(offset)
+    }
+}
+
+struct BudgetedIter<'a, 'b: 'a> {
+    strategy: &'a Budgeted<'b>,
+    new: &'a [u8],
+    options: DiffOptions,
+
+    /// The current stage's matches, or `None` once it's the literal stage.
+    inner: Option<Box<dyn Iterator<Item=Match> + 'a>>,
+    limit: Option<SearchLimit>,
+    started: Instant,
+
+    /// How much of `new` has been covered.
+    pos: usize,
+}
+
+impl<'a, 'b> BudgetedIter<'a, 'b> {
+    /// Where in `new` the current stage stopped searching, if it has.
+    fn stopped_at(&self) -> Option<usize> {
+        let stop = self.limit.as_ref()?.stopped_at()?;
+        Some(stop - self.options.new_offset)
+    }
+
+    /// Finishes the current stage's report, and starts the next one.
+    fn advance(&mut self) {
+        let mut report = self.strategy.report.borrow_mut();
+
+        let stage = {
+            let last = report.last_mut().unwrap();
+            last.range.end = self.pos;
+            last.elapsed = self.started.elapsed();
+            last.stage
+        };
+
+        self.started = Instant::now();
+
+        match (stage, self.strategy.fallback) {
+            (Stage::Primary, Some(fallback)) => {
+                self.limit = self.strategy.fallback_budget.limit();
+                self.inner = Some(fallback.matches(&self.new[self.pos..], &DiffOptions {
+                    limit: self.limit.clone(),
+                    new_offset: self.options.new_offset + self.pos,
+                    ..self.options.clone()
+                }));
+
+                report.push(StageReport {
+                    stage: Stage::Fallback,
+                    range: self.pos..self.pos,
+                    elapsed: Duration::from_secs(0),
+                });
+            }
+            _ => {
+                self.limit = None;
+                self.inner = None;
+
+                report.push(StageReport {
+                    stage: Stage::Literal,
+                    range: self.pos..self.pos,
+                    elapsed: Duration::from_secs(0),
+                });
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        let mut report = self.strategy.report.borrow_mut();
+        let last = report.last_mut().unwrap();
+        last.range.end = self.pos;
+        last.elapsed = self.started.elapsed();
+    }
+}
+
+impl<'a, 'b> Iterator for BudgetedIter<'a, 'b> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        loop {
+            if self.pos == self.new.len() {
+                return None;
+            }
+
+            if self.inner.is_some() && self.stopped_at().map_or(false, |stop| self.pos >= stop) {
+                self.advance();
+                continue;
+            }
+
+            let mut m = match self.inner {
+                Some(ref mut inner) => match inner.next() {
+                    Some(m) => m,
+                    None => {
+                        self.finish();
+                        return None;
+                    }
+                },
+                None => Match {
+                    matched: Default::default(),
+                    unmatched_suffix: self.new.len() - self.pos,
+                },
+            };
+
+            let match_end = self.pos + m.matched.len();
+
+            // Once the stage has stopped searching, everything after that is
+            // left unmatched; the next stage can do better.
+            if let Some(stop) = self.stopped_at() {
+                if self.pos >= stop {
+                    continue;
+                }
+                m.unmatched_suffix = min(m.unmatched_suffix, stop.saturating_sub(match_end));
+            }
+
+            self.pos = match_end + m.unmatched_suffix;
+
+            if self.pos == self.new.len() {
+                self.finish();
+            }
+
+            if m.matched.len() > 0 || m.unmatched_suffix > 0 {
+                return Some(m);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::Index;
+    use format::bsdiff::{generate_full_patch, generate_idempotent_patch};
+    use hash_chain::HashChainIndex;
+    use matcher::chunked::Chunked;
+    use test_util::{assert_roundtrip, pseudo_random, OLD, NEW};
+
+    fn stages(report: &[StageReport]) -> Vec<(Stage, Range<usize>)> {
+        report.iter().map(|r| (r.stage, r.range.clone())).collect()
+    }
+
+    #[test]
+    fn test_unlimited_budget() {
+        let index = Index::compute(OLD.to_vec());
+        let budgeted = Budgeted::new(&index, Budget::Time(Duration::from_secs(3600)));
+
+        assert_eq!(generate_full_patch(&budgeted, NEW, &Default::default()),
+            generate_full_patch(&index, NEW, &Default::default()));
+        assert_eq!(stages(&budgeted.report()), vec![(Stage::Primary, 0..NEW.len())]);
+    }
+
+    #[test]
+    fn test_fallback() {
+        let index = Index::compute(OLD.to_vec());
+        let quick = HashChainIndex::compute(OLD.to_vec(), 1);
+        let budgeted = Budgeted::new(&index, Budget::Searches(2))
+            .with_fallback(&quick, Budget::Searches(2));
+
+        assert_roundtrip(&budgeted, NEW, &Default::default());
+
+        let report = stages(&budgeted.report());
+        assert_eq!(report.iter().map(|r| r.0).collect::<Vec<_>>(), vec![Stage::Primary, Stage::Fallback, Stage::Literal]);
+        assert_eq!(report[0].1.start, 0);
+        assert_eq!(report[0].1.end, report[1].1.start);
+        assert_eq!(report[1].1.end, report[2].1.start);
+        assert_eq!(report[2].1.end, NEW.len());
+    }
+
+    #[test]
+    fn test_no_budget_is_idempotent_patch() {
+        let index = Index::compute(OLD.to_vec());
+        let budgeted = Budgeted::new(&index, Budget::Searches(0));
+
+        assert_eq!(generate_full_patch(&budgeted, NEW, &Default::default()), generate_idempotent_patch(NEW));
+        assert_eq!(stages(&budgeted.report()), vec![(Stage::Primary, 0..0), (Stage::Literal, 0..NEW.len())]);
+    }
+
+    #[test]
+    fn test_budget_stops_scan_of_dissimilar_data() {
+        // Nothing in new matches, so a single scan would run to the end
+        // without yielding a match.
+        let old = pseudo_random(1 << 16, 1);
+        let new = pseudo_random(1 << 20, 2);
+        let index = Index::compute(old);
+
+        let budgeted = Budgeted::new(&index, Budget::Searches(10));
+        let matches = budgeted.matches(&new, &Default::default()).collect::<Vec<_>>();
+        assert_eq!(matches.iter().map(|m| m.matched.len() + m.unmatched_suffix).sum::<usize>(), new.len());
+
+        let report = stages(&budgeted.report());
+        assert_eq!(report.iter().map(|r| r.0).collect::<Vec<_>>(), vec![Stage::Primary, Stage::Literal]);
+        assert!(report[0].1.end <= 10 * 8, "primary covered {:?}", report[0].1);
+
+        let budgeted = Budgeted::new(&index, Budget::Time(Duration::from_millis(5)));
+        assert_eq!(budgeted.matches(&new, &Default::default()).count(), 2);
+        assert!(budgeted.report()[0].range.end < new.len());
+    }
+
+    #[test]
+    fn test_budget_keeps_chunked_matches() {
+        // Every chunk is matched up front, so most of the budget has been
+        // spent before the first match is taken.
+        // Each chunk starts with bytes that cost a search apiece.
+        let old = pseudo_random(1 << 12, 1);
+        let mut new = Vec::new();
+        for k in 0..13 {
+            new.extend(pseudo_random(40, 2 + k as u32));
+            new.extend_from_slice(&old[k * 250..k * 250 + 960]);
+        }
+
+        let index = Index::compute(old.clone());
+        let chunked = Chunked {
+            inner: &index,
+            chunk_size: 1000,
+            threads: 1,
+        };
+
+        let budgeted = Budgeted::new(&chunked, Budget::Searches(50));
+        let matches = budgeted.matches(&new, &Default::default()).collect::<Vec<_>>();
+        assert_eq!(matches.iter().map(|m| m.matched.len() + m.unmatched_suffix).sum::<usize>(), new.len());
+
+        // The primary stage covers the chunks it searched within budget, up
+        // to where it stopped, in the middle of a chunk.
+        let report = stages(&budgeted.report());
+        assert_eq!(report.iter().map(|r| r.0).collect::<Vec<_>>(), vec![Stage::Primary, Stage::Literal]);
+        let stop = report[0].1.end;
+        assert!(stop > 1000 && stop % 1000 != 0, "primary covered {:?}", report[0].1);
+
+        let matched = matches.iter().map(|m| m.matched.len()).sum::<usize>();
+        assert!(matched >= 960, "matched {} of {}", matched, stop);
+
+        assert_roundtrip(&budgeted, &new, &Default::default());
+    }
+}
@@ -0,0 +1,181 @@
+use std::ops::Range;
+use std::sync::Mutex;
+use std::thread;
+
+use diff::{DiffOptions, Match, MatchStrategy};
+
+/// Wraps another strategy, and finds matches for fixed-size chunks of the new
+/// data in parallel.
+///
+/// Each chunk is matched on its own, as if it were a whole file, and the
+/// results are concatenated in order.  Patch writers seek the old file from
+/// one match to the next, so the stitched matches make a valid patch in any
+/// format.  Chunk boundaries don't depend on `threads`, so neither does the
+/// patch.
+///
+/// No match crosses a chunk boundary, which costs a command or so per chunk;
+/// wrapping the result in `Consolidated` merges most of them back together.
+pub struct Chunked<'a, S: 'a> {
+    pub inner: &'a S,
+    pub chunk_size: usize,
+    pub threads: usize,
+}
+
+impl<'a, S> Chunked<'a, S> {
+    pub fn new(inner: &'a S, threads: usize) -> Chunked<'a, S> {
+        Chunked {
+            inner: inner,
+            chunk_size: 1 << 20,
+            threads: threads,
+        }
+    }
+}
+
+impl<'a, S: MatchStrategy + Sync> MatchStrategy for Chunked<'a, S> {
+    fn old(&self) -> &[u8] {
+        self.inner.old()
+    }
+
+    fn matches<'b>(&'b self, new: &'b [u8], options: &DiffOptions) -> Box<dyn Iterator<Item=Match> + 'b> {
+        assert!(self.chunk_size > 0, "chunk_size must be positive");
+
+        let chunks = new.chunks(self.chunk_size).collect::<Vec<_>>();
+        let results = chunks.iter().map(|_| Mutex::new(Vec::new())).collect::<Vec<_>>();
+        let queue = Mutex::new(chunks.iter().enumerate());
+
+        thread::scope(|scope| {
+            for _ in 0..self.threads.max(1) {
+                scope.spawn(|| {
+                    loop {
+                        let (k, chunk) = match queue.lock().unwrap().next() {
+                            Some(next) => next,
+                            None => break,
+                        };
+
+                        println!("Matching chunk {} / {}", k + 1, chunks.len());
+
+                        let options = DiffOptions {
+                            new_offset: options.new_offset + k * self.chunk_size,
+                            ..options.clone()
+                        };
+                        let matches = self.inner.matches(chunk, &options).collect::<Vec<_>>();
+                        *results[k].lock().unwrap() = matches;
+                    }
+                });
+            }
+        });
+
+        Box::new(results.into_iter().flat_map(|r| r.into_inner().unwrap()))
+    }
+
+    fn reference_range(&self, offset: usize) -> Range<usize> {
+        self.inner.reference_range(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+    use std::sync::Arc;
+
+    use super::*;
+    use diff::{dump_trace, Decision, Index, TraceHook};
+    use test_util::{assert_roundtrip, pseudo_random, OLD, NEW};
+
+    #[test]
+    fn test_chunked_patch_roundtrip() {
+        // Long matches with the odd change, so most of them span chunks.
+        let old = pseudo_random(4000, 1);
+        let mut new = b"new header".to_vec();
+        new.extend(old.iter().enumerate().map(|(i, &b)| if i % 700 == 350 { !b } else { b }));
+
+        let index = Index::compute(old.clone());
+        let options = Default::default();
+        let serial = index.matches(&new, &options).collect::<Vec<_>>();
+
+        let mut expected = None;
+        for &threads in &[1, 2, 3, 8] {
+            let strategy = Chunked {
+                inner: &index,
+                chunk_size: 256,
+                threads: threads,
+            };
+
+            let matches = strategy.matches(&new, &options).collect::<Vec<_>>();
+
+            // Every chunk boundary falls between matches, and together they
+            // cover all of new.
+            let mut ends = vec![0];
+            for m in &matches {
+                let end = ends[ends.len() - 1] + m.matched.len() + m.unmatched_suffix;
+                ends.push(end);
+            }
+            assert_eq!(ends[ends.len() - 1], new.len());
+            for boundary in (0..new.len()).step_by(256) {
+                assert!(ends.contains(&boundary), "no match ends at {}", boundary);
+            }
+
+            // Splitting only loses a little around each boundary.
+            let matched = |matches: &[Match]| matches.iter().map(|m| m.matched.len()).sum::<usize>();
+            assert!(matched(&matches) * 100 >= matched(&serial) * 95);
+
+            let patch = assert_roundtrip(&strategy, &new, &options);
+
+            match expected {
+                None => expected = Some((matches, patch)),
+                Some(ref expected) => assert!(expected == &(matches, patch), "threads: {}", threads),
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunked_trace_positions() {
+        let old = pseudo_random(4000, 1);
+        let mut new = pseudo_random(100, 2);
+        new.extend_from_slice(&old[1000..2000]);
+
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let options = DiffOptions {
+            trace: Some({
+                let trace = trace.clone();
+                TraceHook::new(move |d: &Decision| trace.lock().unwrap().push(d.clone()))
+            }),
+            ..Default::default()
+        };
+
+        let index = Index::compute(old);
+        let strategy = Chunked {
+            inner: &index,
+            chunk_size: 256,
+            threads: 3,
+        };
+        strategy.matches(&new, &options).count();
+
+        // Decisions cover the whole new data between them, each within the
+        // chunk it was made in.
+        let trace = trace.lock().unwrap();
+        let mut covered = vec![0; new.len()];
+        for d in trace.iter() {
+            let covers = d.covers();
+            assert_eq!((covers.end - 1) / 256, d.position / 256, "{:?}", d);
+            for i in covers {
+                covered[i] += 1;
+            }
+        }
+        assert!(covered.iter().all(|&c| c > 0));
+
+        let mut dump = Vec::new();
+        dump_trace(&trace, 600..601, &mut dump).unwrap();
+        assert_eq!(str::from_utf8(&dump).unwrap(), "       512 Accepted: old 1412 len 256 extended -0 +0, stopped by EndOfData / EndOfData\n");
+    }
+
+    #[test]
+    fn test_single_chunk_matches_inner() {
+        let index = Index::compute(OLD.to_vec());
+        let options = Default::default();
+
+        let strategy = Chunked::new(&index, 4);
+        assert_eq!(strategy.matches(NEW, &options).collect::<Vec<_>>(),
+            index.matches(NEW, &options).collect::<Vec<_>>());
+    }
+}
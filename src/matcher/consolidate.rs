@@ -0,0 +1,264 @@
+use std::cmp::min;
+use std::ops::Range;
+
+use diff::{Delta, DiffOptions, Match, MatchStrategy};
+use matcher::optimal::CostModel;
+
+/// Wraps another strategy, and tidies up the matches it finds.
+///
+/// For each pair of neighbouring matches, the extra data between them is
+/// either merged into a single delta (when the second match continues the
+/// first one's diagonal), or split between the first match's delta extended
+/// forwards, the second match's delta extended backwards, and what's left as
+/// extra data.  Whichever is cheapest under `cost` wins.
+pub struct Consolidated<'a, S: 'a> {
+    pub inner: &'a S,
+    pub cost: CostModel,
+}
+
+impl<'a, S> Consolidated<'a, S> {
+    pub fn new(inner: &'a S) -> Consolidated<'a, S> {
+        Consolidated {
+            inner: inner,
+            cost: CostModel::default(),
+        }
+    }
+}
+
+impl<'a, S: MatchStrategy> MatchStrategy for Consolidated<'a, S> {
+    fn old(&self) -> &[u8] {
+        self.inner.old()
+    }
+
+    fn matches<'b>(&'b self, new: &'b [u8], options: &DiffOptions) -> Box<dyn Iterator<Item=Match> + 'b> {
+        Box::new(Consolidate {
+            strategy: self,
+            inner: self.inner.matches(new, options),
+            new: new,
+            cur: None,
+        })
+    }
+
+    fn reference_range(&self, offset: usize) -> Range<usize> {
+        self.inner.reference_range(offset)
+    }
+}
+
+struct Consolidate<'a, 'b: 'a, S: 'b> {
+    strategy: &'a Consolidated<'b, S>,
+    inner: Box<dyn Iterator<Item=Match> + 'a>,
+    new: &'a [u8],
+
+    /// The match that may still be merged with or extended into the next
+    /// one, and where its delta starts in `new`.
+    cur: Option<(usize, Match)>,
+}
+
+enum Combined {
+    Merged(Match),
+    Split(Match, usize, Match),
+}
+
+impl<'a, 'b, S: MatchStrategy> Consolidate<'a, 'b, S> {
+    /// Bits saved, relative to leaving them as extra data, by covering bytes
+    /// of `new` with deltas against bytes of `old`, given as `(old, new)`
+    /// position pairs.  The `n`th entry is the saving for the first `n` pairs.
+    fn savings<I: Iterator<Item=(usize, usize)>>(&self, pairs: I) -> Vec<i64> {
+        let old = self.strategy.old();
+        let cost = &self.strategy.cost;
+
+        let mut res = vec![0];
+        let mut total = 0;
+        for (o, n) in pairs {
+            total += cost.extra as i64 - cost.delta(old[o], self.new[n]) as i64;
+            res.push(total);
+        }
+        res
+    }
+
+    fn combine(&self, mut a: Match, b_start: usize, mut b: Match) -> Combined {
+        let gap = a.unmatched_suffix;
+        let gap_start = b_start - gap;
+        let a_old_end = a.matched.old_offset + a.matched.len();
+
+        // How far `a` could extend forwards, and `b` backwards, into the gap.
+        let forward_limit = if a.matched.len() > 0 {
+            min(gap, self.strategy.reference_range(a.matched.old_offset).end - a_old_end)
+        } else {
+            0
+        };
+        let backward_limit = if b.matched.len() > 0 {
+            min(gap, b.matched.old_offset - self.strategy.reference_range(b.matched.old_offset).start)
+        } else {
+            0
+        };
+
+        let forward = self.savings((0..forward_limit).map(|k| (a_old_end + k, gap_start + k)));
+        let backward = self.savings((1..backward_limit + 1).map(|k| (b.matched.old_offset - k, b_start - k)));
+
+        // best_backward[t] is the best backward extension of at most t bytes.
+        let mut best_backward = Vec::with_capacity(backward.len());
+        for y in 0..backward.len() {
+            best_backward.push(if y > 0 && backward[best_backward[y - 1]] >= backward[y] { best_backward[y - 1] } else { y });
+        }
+
+        let (mut x, mut y) = (0, 0);
+        for fx in 0..forward.len() {
+            let fy = best_backward[min(gap - fx, backward.len() - 1)];
+            if forward[fx] + backward[fy] > forward[x] + backward[y] {
+                x = fx;
+                y = fy;
+            }
+        }
+
+        let same_diagonal = a.matched.len() > 0 && b.matched.len() > 0 &&
+            a.matched.reference == b.matched.reference &&
+            a_old_end + gap == b.matched.old_offset;
+
+        if same_diagonal && forward.len() > gap && forward[gap] + self.strategy.cost.command as i64 > forward[x] + backward[y] {
+            return Combined::Merged(Match {
+                matched: Delta {
+                    reference: a.matched.reference,
+                    old_offset: a.matched.old_offset,
+                    lower_delta_len: a.matched.lower_delta_len,
+                    mid_exact_len: a.matched.mid_exact_len,
+                    upper_delta_len: a.matched.upper_delta_len + gap + b.matched.len(),
+                },
+                unmatched_suffix: b.unmatched_suffix,
+            });
+        }
+
+        a.matched.upper_delta_len += x;
+        a.unmatched_suffix -= x + y;
+
+        b.matched.old_offset -= y;
+        b.matched.lower_delta_len += y;
+
+        Combined::Split(a, b_start - y, b)
+    }
+}
+
+impl<'a, 'b, S: MatchStrategy> Iterator for Consolidate<'a, 'b, S> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        loop {
+            let next = match self.inner.next() {
+                Some(m) => m,
+                None => return self.cur.take().map(|(_, m)| m),
+            };
+
+            let (start, cur) = match self.cur.take() {
+                Some(cur) => cur,
+                None => {
+                    self.cur = Some((0, next));
+                    continue;
+                }
+            };
+
+            let next_start = start + cur.matched.len() + cur.unmatched_suffix;
+
+            match self.combine(cur, next_start, next) {
+                Combined::Merged(m) => {
+                    self.cur = Some((start, m));
+                }
+                Combined::Split(a, b_start, b) => {
+                    self.cur = Some((b_start, b));
+                    if a.matched.len() > 0 || a.unmatched_suffix > 0 {
+                        return Some(a);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::Index;
+    use test_util::{assert_roundtrip, OLD, NEW};
+
+    struct Fixed {
+        old: Vec<u8>,
+        matches: fn() -> Vec<Match>,
+    }
+
+    impl MatchStrategy for Fixed {
+        fn old(&self) -> &[u8] {
+            &self.old
+        }
+
+        fn matches<'a>(&'a self, _new: &'a [u8], _options: &DiffOptions) -> Box<dyn Iterator<Item=Match> + 'a> {
+            Box::new((self.matches)().into_iter())
+        }
+    }
+
+    fn exact(old_offset: usize, len: usize, unmatched_suffix: usize) -> Match {
+        Match {
+            matched: Delta {
+                mid_exact_len: len,
+                old_offset: old_offset,
+                ..Default::default()
+            },
+            unmatched_suffix: unmatched_suffix,
+        }
+    }
+
+    #[test]
+    fn test_merge_same_diagonal() {
+        let fixed = Fixed {
+            old: b"abcdefghijklmnopqrstuvwxyz".to_vec(),
+            matches: || vec![exact(0, 13, 1), exact(14, 12, 0)],
+        };
+        let strategy = Consolidated::new(&fixed);
+
+        let matches = strategy.matches(b"abcdefghijklmXopqrstuvwxyz", &Default::default()).collect::<Vec<_>>();
+
+        assert_eq!(matches, vec![Match {
+            matched: Delta {
+                mid_exact_len: 13,
+                upper_delta_len: 13,
+                ..Default::default()
+            },
+            unmatched_suffix: 0,
+        }]);
+    }
+
+    #[test]
+    fn test_extend_into_gap() {
+        // The second match could start 3 bytes earlier.
+        let fixed = Fixed {
+            old: b"0123456789ABCDEFGHIJabcdefghijklmnop".to_vec(),
+            matches: || vec![exact(0, 10, 6), exact(23, 13, 0)],
+        };
+        let strategy = Consolidated::new(&fixed);
+
+        let matches = strategy.matches(b"0123456789xyzabcdefghijklmnop", &Default::default()).collect::<Vec<_>>();
+
+        assert_eq!(matches, vec![
+            exact(0, 10, 3),
+            Match {
+                matched: Delta {
+                    old_offset: 20,
+                    lower_delta_len: 3,
+                    mid_exact_len: 13,
+                    ..Default::default()
+                },
+                unmatched_suffix: 0,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_consolidated_patch_roundtrip() {
+        let index = Index::compute(OLD.to_vec());
+        let strategy = Consolidated::new(&index);
+        let options = Default::default();
+
+        let cost = strategy.cost;
+        assert!(cost.cost(OLD, NEW, strategy.matches(NEW, &options)) <= cost.cost(OLD, NEW, index.matches(NEW, &options)));
+
+        assert_roundtrip(&strategy, NEW, &options);
+    }
+}
@@ -0,0 +1,97 @@
+/// Estimates how many bits bytes of a patch compress to, from how often each
+/// byte value occurs (an order-0 entropy model).
+///
+/// Extra data is priced by the byte frequencies of the whole new file.  Delta
+/// bytes are priced by the frequencies of the delta bytes recorded so far,
+/// starting from one of each, so that zeros get cheaper as exact matches are
+/// found.  Bytes that are common in the new file can then be cheaper as extra
+/// data than as a delta against dissimilar old data, even though the delta
+/// has more zeros in it.
+pub struct EntropyModel {
+    extra_bits: Vec<f64>,
+    delta_counts: Vec<u64>,
+    delta_total: u64,
+}
+
+impl EntropyModel {
+    pub fn new(new: &[u8]) -> EntropyModel {
+        let mut counts = vec![0u64; 256];
+        for &b in new {
+            counts[b as usize] += 1;
+        }
+
+        let total = new.len() as f64;
+
+        EntropyModel {
+            extra_bits: counts.iter().map(|&c| if c > 0 { (total / c as f64).log2() } else { 8.0 }).collect(),
+            delta_counts: vec![1; 256],
+            delta_total: 256,
+        }
+    }
+
+    pub fn extra_cost(&self, new: u8) -> f64 {
+        self.extra_bits[new as usize]
+    }
+
+    pub fn delta_cost(&self, old: u8, new: u8) -> f64 {
+        let count = self.delta_counts[new.wrapping_sub(old) as usize];
+        (self.delta_total as f64 / count as f64).log2()
+    }
+
+    /// Records the delta bytes between `old` and `new`.
+    pub fn record_delta(&mut self, old: &[u8], new: &[u8]) {
+        for (&o, &n) in old.iter().zip(new) {
+            self.delta_counts[n.wrapping_sub(o) as usize] += 1;
+        }
+        self.delta_total += old.len() as u64;
+    }
+
+    /// Records `len` zero delta bytes, for an exact match.
+    pub fn record_exact(&mut self, len: usize) {
+        self.delta_counts[0] += len as u64;
+        self.delta_total += len as u64;
+    }
+
+    /// Of a partial match extension over the given `(old, new)` byte pairs,
+    /// returns how many of the first pairs are worth covering with a delta
+    /// rather than leaving as extra data.
+    pub fn worth_covering<I: Iterator<Item=(u8, u8)>>(&self, pairs: I) -> usize {
+        let mut best = 0;
+        let mut best_saving = 0.0;
+        let mut saving = 0.0;
+
+        for (k, (o, n)) in pairs.enumerate() {
+            saving += self.extra_cost(n) - self.delta_cost(o, n);
+            if saving > best_saving {
+                best = k + 1;
+                best_saving = saving;
+            }
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entropy_costs() {
+        let mut model = EntropyModel::new(b"aaaaaaab");
+
+        assert_eq!(model.extra_cost(b'a'), (8.0f64 / 7.0).log2());
+        assert_eq!(model.extra_cost(b'b'), 3.0);
+        assert_eq!(model.delta_cost(1, 2), 8.0);
+
+        model.record_exact(256);
+        assert_eq!(model.delta_cost(7, 7), (512.0f64 / 257.0).log2());
+        assert_eq!(model.delta_cost(1, 2), 9.0);
+
+        // A delta is worth it for b, which is rare in the new file, but not
+        // for a, which is cheap as extra data.
+        assert_eq!(model.worth_covering(vec![(b'b', b'b'), (0, b'b'), (0, b'a'), (0, b'a')].into_iter()), 1);
+        assert_eq!(model.worth_covering(vec![(b'a', b'a'), (b'b', b'b')].into_iter()), 2);
+        assert_eq!(model.worth_covering(vec![(0, b'a')].into_iter()), 0);
+    }
+}
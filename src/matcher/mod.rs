@@ -0,0 +1,8 @@
+pub mod bsdiff43;
+pub mod budget;
+pub mod chunked;
+pub mod consolidate;
+pub mod entropy;
+pub mod optimal;
+pub mod repeats;
+pub mod seed_extend;
@@ -0,0 +1,404 @@
+use std::cmp::{min, max};
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::Range;
+
+use diff::{Index, Delta, DiffOptions, Match, MatchIndex, MatchStrategy, longest_prefix};
+
+/// Once the cheapest path has been copying exactly for this many bytes, stop
+/// looking for other candidates until it stops matching.  This keeps long
+/// identical stretches from costing a suffix array search per byte.
+const SUFFICIENT_LEN: usize = 128;
+
+/// How many copy states are kept at each position.
+const BEAM: usize = 16;
+
+/// Estimated cost, in bits, of each part of an encoded patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CostModel {
+    /// A command, paid once per match.
+    pub command: u64,
+
+    /// A delta byte where old and new agree, which compresses to almost
+    /// nothing.
+    pub zero_delta: u64,
+
+    /// A delta byte where old and new differ.
+    pub nonzero_delta: u64,
+
+    /// A byte copied from new as extra data.
+    pub extra: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> CostModel {
+        CostModel {
+            command: 64,
+            zero_delta: 0,
+            nonzero_delta: 9,
+            extra: 8,
+        }
+    }
+}
+
+impl CostModel {
+    /// The estimated cost of a patch built from `matches`.
+    pub fn cost<M: IntoIterator<Item=Match>>(&self, old: &[u8], new: &[u8], matches: M) -> u64 {
+        let mut total = 0;
+        let mut i = 0;
+
+        for m in matches {
+            let d = &m.matched;
+
+            total += self.command;
+            for k in 0..d.len() {
+                total += self.delta(old[d.old_offset + k], new[i + k]);
+            }
+            total += m.unmatched_suffix as u64 * self.extra;
+
+            i += d.len() + m.unmatched_suffix;
+        }
+
+        total
+    }
+
+    /// The cost of one delta byte between `old` and `new`.
+    pub fn delta(&self, old: u8, new: u8) -> u64 {
+        if old == new { self.zero_delta } else { self.nonzero_delta }
+    }
+}
+
+/// Chooses the sequence of matches with the lowest estimated cost under a
+/// `CostModel`, instead of greedily taking the longest match at each position
+/// like `MatchIter`.  A shorter match that lines up with a long one often
+/// beats a longer match that has to be followed by extra data.
+///
+/// At each position of `new`, the suffixes either side of it in the suffix
+/// array are candidates for starting a copy.  The parse keeps the cheapest
+/// ways of reaching each position (extra data, or copying along one of a
+/// bounded number of diagonals), and commits to the cheapest path at the end
+/// of every `window` bytes.
+pub struct OptimalStrategy<'a> {
+    pub index: &'a Index,
+    pub cost: CostModel,
+
+    /// How many suffixes either side of the search position are considered.
+    pub candidates: usize,
+
+    /// How many bytes of `new` are parsed before committing to a path.  The
+    /// parse holds a few hundred bytes of state per byte of window.
+    pub window: usize,
+}
+
+impl<'a> OptimalStrategy<'a> {
+    pub fn new(index: &'a Index) -> OptimalStrategy<'a> {
+        OptimalStrategy {
+            index: index,
+            cost: CostModel::default(),
+            candidates: 4,
+            window: 4096,
+        }
+    }
+}
+
+impl<'a> MatchStrategy for OptimalStrategy<'a> {
+    fn old(&self) -> &[u8] {
+        &self.index.data
+    }
+
+    /// Only exact matches of at least `options.min_match_len` bytes start a
+    /// new copy; the other options don't apply.
+    fn matches<'b>(&'b self, new: &'b [u8], options: &DiffOptions) -> Box<dyn Iterator<Item=Match> + 'b> {
+        Box::new(OptimalParse {
+            index: self.index,
+            new: new,
+            min_match_len: max(1, options.min_match_len),
+            cost: self.cost,
+            candidates: self.candidates,
+            window: self.window,
+            pos: 0,
+            carry: State::Start,
+            exact_run: 0,
+            pending: Default::default(),
+            ready: VecDeque::new(),
+        })
+    }
+
+    fn reference_range(&self, offset: usize) -> Range<usize> {
+        self.index.reference_bounds(offset).1
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Nothing has been encoded yet.
+    Start,
+
+    /// Appending extra data to the current command.
+    Literal,
+
+    /// Copying with deltas from `old[i + diagonal]` for each position `i` of
+    /// new, within the reference ending at `end`.
+    Copy { diagonal: isize, reference: usize, end: usize },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    state: State,
+    cost: u64,
+
+    /// The node in the previous position's layer this one was reached from.
+    prev: usize,
+
+    /// Whether reaching this node started a new command.
+    fresh: bool,
+}
+
+struct OptimalParse<'a> {
+    index: &'a Index,
+    new: &'a [u8],
+    min_match_len: usize,
+    cost: CostModel,
+    candidates: usize,
+    window: usize,
+
+    /// How much of `new` has been parsed.
+    pos: usize,
+
+    /// The state the parse committed to at `pos`.
+    carry: State,
+    exact_run: usize,
+
+    pending: Match,
+    ready: VecDeque<Match>,
+}
+
+impl<'a> OptimalParse<'a> {
+    /// Parses the next window of `new`, and queues the matches along the
+    /// cheapest path through it.
+    fn parse_window(&mut self) {
+        let end = min(self.new.len(), self.pos + self.window);
+
+        let mut layers = Vec::with_capacity(end - self.pos + 1);
+        layers.push(vec![Node {
+            state: self.carry,
+            cost: 0,
+            prev: 0,
+            fresh: false,
+        }]);
+
+        for i in self.pos..end {
+            let next = self.step(&layers[i - self.pos], i);
+            layers.push(next);
+        }
+
+        let last = &layers[layers.len() - 1];
+        let mut k = (0..last.len()).min_by_key(|&k| last[k].cost).unwrap();
+
+        let mut path = Vec::with_capacity(end - self.pos);
+        for layer in layers[1..].iter().rev() {
+            path.push(layer[k]);
+            k = layer[k].prev;
+        }
+        path.reverse();
+
+        for (i, node) in (self.pos..end).zip(path.iter()) {
+            self.record(i, node);
+        }
+
+        self.carry = path[path.len() - 1].state;
+        self.pos = end;
+    }
+
+    /// Returns the cheapest ways of reaching position `i + 1`, given the
+    /// ways of reaching position `i`.
+    fn step(&mut self, layer: &[Node], i: usize) -> Vec<Node> {
+        let old = &self.index.data;
+        let byte = self.new[i];
+
+        let best = (0..layer.len()).min_by_key(|&k| layer[k].cost).unwrap();
+
+        let mut next = Vec::with_capacity(BEAM + 2 * self.candidates + 1);
+
+        // Extra data can follow anything, but the very first byte needs a
+        // command to belong to.
+        let literal = (0..layer.len()).min_by_key(|&k| {
+            layer[k].cost + if layer[k].state == State::Start { self.cost.command } else { 0 }
+        }).unwrap();
+        let fresh = layer[literal].state == State::Start;
+        next.push(Node {
+            state: State::Literal,
+            cost: layer[literal].cost + self.cost.extra + if fresh { self.cost.command } else { 0 },
+            prev: literal,
+            fresh: fresh,
+        });
+
+        for (k, node) in layer.iter().enumerate() {
+            if let State::Copy { diagonal, end, .. } = node.state {
+                let p = (i as isize + diagonal) as usize;
+                if p < end {
+                    next.push(Node {
+                        state: node.state,
+                        cost: node.cost + self.cost.delta(old[p], byte),
+                        prev: k,
+                        fresh: false,
+                    });
+                }
+            }
+        }
+
+        if self.exact_run < SUFFICIENT_LEN && self.new.len() - i >= self.min_match_len {
+            let buf = &self.new[i..];
+            let rank = self.index.rank_of(buf);
+
+            for r in rank.saturating_sub(self.candidates) .. min(old.len(), rank + self.candidates) {
+                let p = self.index.suffix_at(r);
+                let (reference, bounds) = self.index.reference_bounds(p);
+                if longest_prefix(&old[p..bounds.end], buf) < self.min_match_len {
+                    continue;
+                }
+
+                next.push(Node {
+                    state: State::Copy {
+                        diagonal: p as isize - i as isize,
+                        reference: reference,
+                        end: bounds.end,
+                    },
+                    cost: layer[best].cost + self.cost.command + self.cost.zero_delta,
+                    prev: best,
+                    fresh: true,
+                });
+            }
+        }
+
+        // Keep the cheapest node per diagonal, and the cheapest diagonals.
+        next[1..].sort_by_key(|n| (n.state_diagonal(), n.cost));
+        next.dedup_by_key(|n| n.state_diagonal());
+        next[1..].sort_by_key(|n| n.cost);
+        next.truncate(BEAM + 1);
+
+        let lead = next.iter().min_by_key(|n| n.cost).unwrap();
+        self.exact_run = match lead.state {
+            State::Copy { diagonal, .. } if old[(i as isize + diagonal) as usize] == byte => self.exact_run + 1,
+            _ => 0,
+        };
+
+        next
+    }
+
+    fn record(&mut self, i: usize, node: &Node) {
+        if node.fresh {
+            let m = mem::take(&mut self.pending);
+            if m.matched.len() > 0 || m.unmatched_suffix > 0 {
+                self.ready.push_back(m);
+            }
+        }
+
+        match node.state {
+            State::Copy { diagonal, reference, .. } => {
+                if node.fresh {
+                    self.pending.matched = Delta {
+                        reference: reference,
+                        old_offset: (i as isize + diagonal) as usize,
+                        ..Default::default()
+                    };
+                }
+                self.pending.matched.lower_delta_len += 1;
+            }
+            State::Literal => {
+                self.pending.unmatched_suffix += 1;
+            }
+            State::Start => unreachable!(),
+        }
+    }
+}
+
+impl Node {
+    /// Sort key grouping nodes by state: the literal node sorts first, and
+    /// copy nodes by diagonal.
+    fn state_diagonal(&self) -> Option<isize> {
+        match self.state {
+            State::Copy { diagonal, .. } => Some(diagonal),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for OptimalParse<'a> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        loop {
+            if let Some(m) = self.ready.pop_front() {
+                return Some(m);
+            }
+
+            if self.pos == self.new.len() {
+                let m = mem::take(&mut self.pending);
+                return if m.matched.len() > 0 || m.unmatched_suffix > 0 { Some(m) } else { None };
+            }
+
+            self.parse_window();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::MatchIter;
+    use test_util::{assert_roundtrip, pseudo_random, Lcg};
+
+    fn edited(old: &[u8]) -> Vec<u8> {
+        let mut new = Vec::new();
+        let mut random = Lcg(7);
+        let mut i = 0;
+        while i < old.len() {
+            let seed = random.next().unwrap();
+            let len = min(old.len() - i, 20 + (seed >> 16) as usize % 60);
+            new.extend_from_slice(&old[i..i + len]);
+            match (seed >> 8) % 4 {
+                0 => new.push(b'!'),
+                1 => { let last = new.len() - 1; new[last] ^= 1; }
+                2 => new.extend_from_slice(&old[(seed as usize) % (old.len() - 16)..][..16]),
+                _ => {}
+            }
+            i += len;
+        }
+        new
+    }
+
+    #[test]
+    fn test_optimal_patch_roundtrip() {
+        let old = pseudo_random(5000, 1).into_iter().map(|b| b % 16).collect::<Vec<_>>();
+        let new = edited(&old);
+
+        let index = Index::compute(old.clone());
+        let strategy = OptimalStrategy {
+            window: 700,
+            ..OptimalStrategy::new(&index)
+        };
+
+        assert_roundtrip(&strategy, &new, &Default::default());
+
+        let model = CostModel::default();
+        let optimal = model.cost(&old, &new, strategy.matches(&new, &Default::default()));
+        let greedy = model.cost(&old, &new, MatchIter::from(&index, &new));
+        assert!(optimal <= greedy, "optimal {} > greedy {}", optimal, greedy);
+    }
+
+    #[test]
+    fn test_optimal_edge_cases() {
+        let index = Index::compute(b"abcdefghijklmnop".to_vec());
+        let strategy = OptimalStrategy::new(&index);
+
+        assert_eq!(strategy.matches(b"", &Default::default()).count(), 0);
+
+        let matches = strategy.matches(b"xyz", &Default::default()).collect::<Vec<_>>();
+        assert_eq!(matches, vec![Match { matched: Default::default(), unmatched_suffix: 3 }]);
+
+        let matches = strategy.matches(b"abcdefghijklmnop", &Default::default()).collect::<Vec<_>>();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].matched.lower_delta_len, 16);
+    }
+}
@@ -0,0 +1,150 @@
+use std::cmp::min;
+use std::ops::Range;
+use std::usize;
+
+use diff::longest_prefix;
+use hash_chain::{hash, HASH_LEN};
+
+/// How far back a self-copy in a `linear_diff` patch can reach, so that
+/// applying it only needs to keep this much of the new data around.
+pub const SELF_COPY_WINDOW: u64 = 1 << 24;
+
+/// Marks the end of a chain.
+const NONE: usize = usize::MAX;
+
+/// Part of the new data that's a copy of bytes earlier in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repeat {
+    /// Where the copy comes from.  The source can overlap the target, for
+    /// runs of a repeating pattern.
+    pub source: usize,
+    pub target: usize,
+    pub len: usize,
+}
+
+/// Finds parts of the new data that repeat something earlier in it, which
+/// `linear_diff::generate_patch_with_repeats` turns into self-copies.
+///
+/// Like `HashChainIndex`, but over the new data, and only ever looking back:
+/// `find` must be called on increasing ranges, and only sees sources before
+/// the target, no further back than `SELF_COPY_WINDOW`.
+pub struct RepeatFinder<'a> {
+    new: &'a [u8],
+
+    /// Shorter repeats are left as extra data, since each copy costs a
+    /// command.
+    pub min_len: usize,
+
+    /// How many earlier positions with the same hash to try.
+    pub depth: usize,
+
+    head: Vec<usize>,
+
+    /// For each position in the window, the previous one with the same hash.
+    chain: Vec<usize>,
+
+    /// Positions before this have been added to the chains.
+    inserted: usize,
+}
+
+impl<'a> RepeatFinder<'a> {
+    pub fn new(new: &'a [u8]) -> RepeatFinder<'a> {
+        RepeatFinder {
+            new: new,
+            min_len: 32,
+            depth: 16,
+            head: vec![NONE; 1 << 20],
+            chain: vec![NONE; min(new.len(), SELF_COPY_WINDOW as usize)],
+            inserted: 0,
+        }
+    }
+
+    fn insert_upto(&mut self, end: usize) {
+        let end = min(end, (self.new.len() + 1).saturating_sub(HASH_LEN));
+        while self.inserted < end {
+            let pos = self.inserted;
+            let h = hash(&self.new[pos..]);
+            let slot = pos % self.chain.len();
+            self.chain[slot] = self.head[h];
+            self.head[h] = pos;
+            self.inserted += 1;
+        }
+    }
+
+    /// The longest repeat of an earlier source at `target`, ending by `end`.
+    fn longest_at(&self, target: usize, end: usize) -> Repeat {
+        let mut best = Repeat {
+            source: 0,
+            target: target,
+            len: 0,
+        };
+
+        let mut pos = self.head[hash(&self.new[target..])];
+        for _ in 0..self.depth {
+            if pos == NONE || pos >= target || target - pos > SELF_COPY_WINDOW as usize {
+                break;
+            }
+
+            let len = longest_prefix(&self.new[pos..end], &self.new[target..end]);
+            if len > best.len {
+                best.source = pos;
+                best.len = len;
+            }
+
+            let next = self.chain[pos % self.chain.len()];
+            if next >= pos {
+                break;
+            }
+            pos = next;
+        }
+
+        best
+    }
+
+    /// Returns non-overlapping repeats within `range`, in order.
+    pub fn find(&mut self, range: Range<usize>) -> Vec<Repeat> {
+        let mut res = Vec::new();
+
+        let mut pos = range.start;
+        while pos + HASH_LEN <= range.end {
+            self.insert_upto(pos);
+
+            let repeat = self.longest_at(pos, range.end);
+            if repeat.len >= self.min_len {
+                res.push(repeat);
+                pos += repeat.len;
+            } else {
+                pos += 1;
+            }
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_repeats() {
+        let mut new = b"a table that isn't in the old file; ".to_vec();
+        new.extend_from_slice(b"something else, then ");
+        new.extend_from_slice(b"a table that isn't in the old file; ");
+        new.extend_from_slice(&[b'z'; 40]);
+
+        let mut finder = RepeatFinder::new(&new);
+        assert_eq!(finder.find(0..new.len()), vec![
+            Repeat {
+                source: 0,
+                target: 57,
+                len: 36,
+            },
+            Repeat {
+                source: 93,
+                target: 94,
+                len: 39,
+            },
+        ]);
+    }
+}
@@ -0,0 +1,207 @@
+use std::cmp::min;
+use std::mem;
+use std::ops::Range;
+
+use diff::{Index, Delta, DiffOptions, Match, MatchIndex, MatchStrategy};
+
+/// Finds short exact seeds, and extends them along their diagonal for as long
+/// as the mismatches stay sparse.
+///
+/// This suits compiled code that has moved: every embedded address changes,
+/// so exact runs are rarely longer than a few bytes, but the region is mostly
+/// unchanged.  The resulting deltas can cover long stretches with scattered
+/// mismatches, where `MatchIter` gives up after `mismatch_budget` of them.
+///
+/// The thresholds are the fields below; `DiffOptions` don't apply.
+pub struct SeedExtend<'a, I: 'a = Index> {
+    pub index: &'a I,
+
+    /// Exact matches at least this long are tried as seeds.
+    pub seed_len: usize,
+
+    /// The extension looks at mismatches in a sliding window this long...
+    pub window: usize,
+
+    /// ...and stops when more than this percentage of it mismatches.
+    pub max_mismatch_percent: usize,
+
+    /// Extended matches shorter than this are dropped, so that seeds that
+    /// match by chance don't turn into commands.
+    pub min_len: usize,
+}
+
+impl<'a, I: MatchIndex> SeedExtend<'a, I> {
+    pub fn new(index: &'a I) -> SeedExtend<'a, I> {
+        SeedExtend {
+            index: index,
+            seed_len: 4,
+            window: 32,
+            max_mismatch_percent: 25,
+            min_len: 32,
+        }
+    }
+}
+
+impl<'a, I: MatchIndex> MatchStrategy for SeedExtend<'a, I> {
+    fn old(&self) -> &[u8] {
+        self.index.data()
+    }
+
+    fn matches<'b>(&'b self, new: &'b [u8], _options: &DiffOptions) -> Box<dyn Iterator<Item=Match> + 'b> {
+        Box::new(SeedExtendIter {
+            strategy: self,
+            new: new,
+            i: 0,
+            last_delta: Default::default(),
+            last_end: 0,
+        })
+    }
+
+    fn reference_range(&self, offset: usize) -> Range<usize> {
+        self.index.reference_bounds(offset).1
+    }
+}
+
+/// Returns how many pairs can be covered, comparing pair `k` with `same(k)`,
+/// before more than `max_mismatches` of any `window` consecutive pairs
+/// differ.  The result always ends on a matching pair.
+fn extend<F: Fn(usize) -> bool>(len: usize, window: usize, max_mismatches: usize, same: F) -> usize {
+    let mut mismatches = 0;
+    let mut res = 0;
+
+    for k in 0..len {
+        let matched = same(k);
+        if !matched {
+            mismatches += 1;
+        }
+        if k >= window && !same(k - window) {
+            mismatches -= 1;
+        }
+        if mismatches > max_mismatches {
+            break;
+        }
+        if matched {
+            res = k + 1;
+        }
+    }
+
+    res
+}
+
+struct SeedExtendIter<'a, 'b: 'a, I: 'b> {
+    strategy: &'a SeedExtend<'b, I>,
+    new: &'a [u8],
+    i: usize,
+    last_delta: Delta,
+    last_end: usize,
+}
+
+impl<'a, 'b, I: MatchIndex> Iterator for SeedExtendIter<'a, 'b, I> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        let s = self.strategy;
+        let old = s.index.data();
+        let new = self.new;
+        let max_mismatches = s.window * s.max_mismatch_percent / 100;
+
+        while self.i < new.len() {
+            // Where the previous match's diagonal would be by now.
+            let hint = self.last_delta.old_offset + self.last_delta.len() + (self.i - self.last_end);
+            let m = s.index.longest_match_near(&new[self.i..], hint);
+
+            if m.len() < s.seed_len {
+                self.i += 1;
+                continue;
+            }
+
+            let (reference, bounds) = s.index.reference_bounds(m.start);
+            let seed_end = self.i + m.len();
+
+            let forward = extend(min(bounds.end - m.end, new.len() - seed_end), s.window, max_mismatches,
+                |k| old[m.end + k] == new[seed_end + k]);
+            let backward = extend(min(m.start - bounds.start, self.i - self.last_end), s.window, max_mismatches,
+                |k| old[m.start - 1 - k] == new[self.i - 1 - k]);
+
+            if backward + m.len() + forward < s.min_len {
+                self.i += 1;
+                continue;
+            }
+
+            let begin = self.i - backward;
+
+            let last_end = self.last_end;
+            self.last_end = seed_end + forward;
+            self.i = self.last_end;
+
+            let last_delta = mem::replace(&mut self.last_delta, Delta {
+                reference: reference,
+                old_offset: m.start - backward,
+                lower_delta_len: backward,
+                mid_exact_len: m.len(),
+                upper_delta_len: forward,
+            });
+
+            if begin > last_end || last_delta.len() > 0 {
+                return Some(Match {
+                    matched: last_delta,
+                    unmatched_suffix: begin - last_end,
+                });
+            }
+        }
+
+        if new.len() > self.last_end || self.last_delta.len() > 0 {
+            let suffix = new.len() - self.last_end;
+            self.last_end = new.len();
+            return Some(Match {
+                matched: mem::take(&mut self.last_delta),
+                unmatched_suffix: suffix,
+            });
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::MatchIter;
+    use test_util::{assert_roundtrip, pseudo_random};
+
+    #[test]
+    fn test_extend() {
+        let same = |pattern: &'static [u8]| move |k: usize| pattern[k] == b'=';
+
+        assert_eq!(extend(8, 4, 1, same(b"========")), 8);
+        assert_eq!(extend(8, 4, 1, same(b"==x===x=")), 8);
+        assert_eq!(extend(8, 4, 1, same(b"==x==x==")), 5);
+        assert_eq!(extend(8, 4, 1, same(b"==xx====")), 2);
+        assert_eq!(extend(8, 4, 1, same(b"===x===x")), 7);
+        assert_eq!(extend(8, 4, 1, same(b"===xx===")), 3);
+    }
+
+    #[test]
+    fn test_sparse_changes() {
+        let old = pseudo_random(4000, 1);
+
+        // Shift by a few bytes, and change every 8th byte, like relocated
+        // addresses would.
+        let mut new = b"abc".to_vec();
+        new.extend(old.iter().enumerate().map(|(i, &b)| if i % 8 == 7 { b.wrapping_add(1) } else { b }));
+
+        let index = Index::compute(old.clone());
+        let options = Default::default();
+
+        // No exact run is longer than 7 bytes, so MatchIter finds nothing.
+        assert_eq!(MatchIter::from(&index, &new).count(), 1);
+
+        let strategy = SeedExtend::new(&index);
+        let matches = strategy.matches(&new, &options).collect::<Vec<_>>();
+        let covered = matches.iter().map(|m| m.matched.len()).sum::<usize>();
+        assert!(matches.len() < 5);
+        assert!(covered > new.len() * 9 / 10);
+
+        assert_roundtrip(&strategy, &new, &options);
+    }
+}
@@ -0,0 +1,126 @@
+use std::cmp::min;
+use std::ops::Range;
+
+use diff::{MatchIndex, longest_prefix};
+use suffix_array::Offsets;
+
+/// Samples are sorted by (at most) this many leading bytes, which bounds the
+/// cost of sorting even when the data has long repeated runs.  Matches longer
+/// than this are recovered by extending the candidate forwards.
+const KEY_LEN: usize = 256;
+
+/// A suffix index that only stores every `step`-th suffix of the data.
+///
+/// This uses `1 / step` of the memory of a full `Index`, at the cost of doing
+/// `step` searches per lookup, and of sometimes finding a shorter match than
+/// a full index would.
+pub struct SparseIndex {
+    pub data: Vec<u8>,
+    step: usize,
+    samples: Offsets,
+}
+
+impl SparseIndex {
+    pub fn compute(data: Vec<u8>, step: usize) -> SparseIndex {
+        assert!(step > 0, "sampling step must be non-zero");
+
+        println!("Sorting");
+
+        let samples = {
+            let key = |pos: usize| &data[pos..min(data.len(), pos + KEY_LEN)];
+
+            // Sorting is stable, so samples with the same key stay in position
+            // order.
+            if Offsets::needs_wide(data.len()) {
+                let mut samples = (0..data.len()).step_by(step).map(|p| p as u64).collect::<Vec<_>>();
+                samples.sort_by(|&a, &b| key(a as usize).cmp(key(b as usize)));
+                Offsets::Wide(samples)
+            } else {
+                let mut samples = (0..data.len()).step_by(step).map(|p| p as u32).collect::<Vec<_>>();
+                samples.sort_by(|&a, &b| key(a as usize).cmp(key(b as usize)));
+                Offsets::Narrow(samples)
+            }
+        };
+
+        SparseIndex {
+            data: data,
+            step: step,
+            samples: samples,
+        }
+    }
+
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    fn key(&self, pos: usize) -> &[u8] {
+        &self.data[pos..min(self.data.len(), pos + KEY_LEN)]
+    }
+}
+
+impl MatchIndex for SparseIndex {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Any match at least `step` bytes long covers a sampled position within
+    /// its first `step` bytes.  So look up each of the first `step` suffixes
+    /// of `buf`, and keep the candidates that also match backwards to the
+    /// start of `buf`.
+    fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+        let mut best = (self.data.len(), 0);
+
+        for skip in 0..min(self.step, buf.len()) {
+            let probe = &buf[skip..min(buf.len(), skip + KEY_LEN)];
+
+            let pivot = match self.samples.binary_search_by(|s| self.key(s).cmp(probe)) {
+                Ok(rank) => rank,
+                Err(rank) => rank,
+            };
+
+            for rank in pivot.saturating_sub(1) .. min(pivot + 1, self.samples.len()) {
+                let sample = self.samples.get(rank);
+                if sample < skip || &self.data[sample - skip..sample] != &buf[..skip] {
+                    continue;
+                }
+
+                let len = skip + longest_prefix(&buf[skip..], &self.data[sample..]);
+                if len > best.1 {
+                    best = (sample - skip, len);
+                }
+            }
+        }
+
+        best.0 .. best.0 + best.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::MatchIter;
+    use test_util::{assert_roundtrip, OLD, NEW};
+
+    #[test]
+    fn test_sparse_longest_match() {
+        let data = b"the quick brown fox jumps over the lazy dog".to_vec();
+        let index = SparseIndex::compute(data.clone(), 4);
+
+        for start in 0..data.len() - 8 {
+            let m = index.longest_match(&data[start..start + 8]);
+            assert_eq!(m.len(), 8);
+            assert_eq!(&data[m], &data[start..start + 8]);
+        }
+
+        assert_eq!(index.longest_match(b"lazy cat").len(), 5);
+    }
+
+    #[test]
+    fn test_sparse_patch_roundtrip() {
+        let index = SparseIndex::compute(OLD.to_vec(), 3);
+
+        assert!(MatchIter::from(&index, NEW).any(|m| m.matched.mid_exact_len > 8));
+
+        assert_roundtrip(&index, NEW, &Default::default());
+    }
+}
@@ -0,0 +1,846 @@
+use std::cmp::{min, Ordering, Reverse};
+use std::ops::Range;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::{thread, u32, u64};
+
+use byteorder::{LittleEndian, ByteOrder};
+use memmap::Mmap;
+
+/// Anything that can appear in the string being sorted.
+trait Symbol: Copy {
+    fn index(self) -> usize;
+}
+
+impl Symbol for u8 {
+    fn index(self) -> usize { self as usize }
+}
+
+impl Symbol for u32 {
+    fn index(self) -> usize { self as usize }
+}
+
+impl Symbol for u64 {
+    fn index(self) -> usize { self as usize }
+}
+
+/// Integer type used to store suffix offsets while (and after) sorting.
+trait Offset: Symbol + Eq + Send {
+    const EMPTY: Self;
+
+    fn from_index(i: usize) -> Self;
+}
+
+impl Offset for u32 {
+    const EMPTY: u32 = u32::MAX;
+
+    fn from_index(i: usize) -> u32 { i as u32 }
+}
+
+impl Offset for u64 {
+    const EMPTY: u64 = u64::MAX;
+
+    fn from_index(i: usize) -> u64 { i as u64 }
+}
+
+/// A sorted suffix array, stored as 32-bit offsets whenever the data is small
+/// enough for that, and as 64-bit offsets otherwise.
+pub enum Offsets {
+    Narrow(Vec<u32>),
+    Wide(Vec<u64>),
+    Mapped(MappedOffsets),
+}
+
+/// A table of little-endian offsets living in a memory-mapped file.
+pub struct MappedOffsets {
+    map: Mmap,
+    start: usize,
+    width: usize,
+    len: usize,
+}
+
+impl MappedOffsets {
+    /// Wraps the `len` offsets of `width` bytes each found at `start` in `map`.
+    pub fn new(map: Mmap, start: usize, width: usize, len: usize) -> MappedOffsets {
+        assert!(width == 4 || width == 8);
+        assert!(start + len * width <= map.len());
+
+        MappedOffsets {
+            map: map,
+            start: start,
+            width: width,
+            len: len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn get(&self, i: usize) -> usize {
+        assert!(i < self.len);
+
+        let p = self.start + i * self.width;
+        let buf = unsafe { &self.map.as_slice()[p..p + self.width] };
+
+        if self.width == 4 {
+            LittleEndian::read_u32(buf) as usize
+        } else {
+            LittleEndian::read_u64(buf) as usize
+        }
+    }
+
+    fn binary_search_by<F>(&self, mut f: F) -> Result<usize, usize>
+        where F: FnMut(usize) -> Ordering
+    {
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match f(self.get(mid)) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(lo)
+    }
+}
+
+impl Offsets {
+    /// Whether data of `len` bytes needs 64-bit offsets.
+    pub fn needs_wide(len: usize) -> bool {
+        len as u64 >= u32::MAX as u64
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            Offsets::Narrow(ref v) => v.len(),
+            Offsets::Wide(ref v) => v.len(),
+            Offsets::Mapped(ref m) => m.len(),
+        }
+    }
+
+    /// The size of a single stored offset, in bytes.
+    pub fn width(&self) -> usize {
+        match *self {
+            Offsets::Narrow(_) => 4,
+            Offsets::Wide(_) => 8,
+            Offsets::Mapped(ref m) => m.width(),
+        }
+    }
+
+    pub fn get(&self, i: usize) -> usize {
+        match *self {
+            Offsets::Narrow(ref v) => v[i] as usize,
+            Offsets::Wide(ref v) => v[i] as usize,
+            Offsets::Mapped(ref m) => m.get(i),
+        }
+    }
+
+    pub fn binary_search_by<F>(&self, mut f: F) -> Result<usize, usize>
+        where F: FnMut(usize) -> Ordering
+    {
+        match *self {
+            Offsets::Narrow(ref v) => v.binary_search_by(|&o| f(o as usize)),
+            Offsets::Wide(ref v) => v.binary_search_by(|&o| f(o as usize)),
+            Offsets::Mapped(ref m) => m.binary_search_by(f),
+        }
+    }
+}
+
+/// Computes the suffix array of `data` in linear time.
+///
+/// The result is ordered the same way as a plain lexicographic sort of
+/// `data[i..]` for every `i`, where a suffix that is a prefix of another sorts
+/// first.  The empty suffix is not included.
+pub fn build(data: &[u8]) -> Offsets {
+    if Offsets::needs_wide(data.len()) {
+        Offsets::Wide(sa_is(data, 255))
+    } else {
+        Offsets::Narrow(sa_is(data, 255))
+    }
+}
+
+/// Bytes, after the two a bucket is keyed on, that suffixes are compared by
+/// before any remaining ties are refined by prefix doubling.
+const SORT_DEPTH: usize = 16;
+
+/// Computes the same suffix array as `build`, using up to `threads` threads.
+///
+/// Suffixes are first bucketed by their leading two bytes, then each bucket is
+/// sorted independently by the next `SORT_DEPTH` bytes.  Suffixes that still
+/// tie are refined by prefix doubling, which sorts them by the rank of the
+/// suffix `h` bytes further on and doubles `h` each round, so long repeats
+/// cost a logarithmic number of rounds rather than a comparison per byte.
+///
+/// Highly repetitive data (runs, short periods, large repeated blocks) leaves
+/// most suffixes tied, and then SA-IS on one thread is faster than any of
+/// this, so if more than a quarter of the suffixes share a bucket or still
+/// tie after the first sort, this falls back to `build`.
+pub fn build_parallel(data: &[u8], threads: usize) -> Offsets {
+    if threads > 1 && data.len() >= 2 {
+        let max_unsorted = data.len() / 4;
+
+        let offsets = if Offsets::needs_wide(data.len()) {
+            bucket_sort(data, threads, max_unsorted).map(Offsets::Wide)
+        } else {
+            bucket_sort(data, threads, max_unsorted).map(Offsets::Narrow)
+        };
+
+        if let Some(offsets) = offsets {
+            return offsets;
+        }
+
+        println!("Data too repetitive to sort in parallel");
+    }
+
+    build(data)
+}
+
+/// Sorts suffixes as described for `build_parallel`, or returns `None` if more
+/// than `max_unsorted` of them share a bucket or tie after the first sort.
+fn bucket_sort<O: Offset + Sync>(data: &[u8], threads: usize, max_unsorted: usize) -> Option<Vec<O>> {
+    let n = data.len();
+
+    // A suffix of length one sorts before every longer suffix that starts with
+    // the same byte, so it gets the first slot of that byte's range.
+    let key = |i: usize| {
+        let second = if i + 1 < n { data[i + 1] as usize + 1 } else { 0 };
+        data[i] as usize * 257 + second
+    };
+
+    let mut starts = vec![0usize; 256 * 257 + 1];
+    for i in 0..n {
+        starts[key(i) + 1] += 1;
+    }
+    for k in 1..starts.len() {
+        if starts[k] > max_unsorted {
+            return None;
+        }
+        starts[k] += starts[k - 1];
+    }
+
+    let mut offsets = vec![O::EMPTY; n];
+    {
+        let mut next = starts.clone();
+        for i in 0..n {
+            let k = key(i);
+            offsets[next[k]] = O::from_index(i);
+            next[k] += 1;
+        }
+    }
+
+    // The bytes each suffix is compared by, after its bucket's two.
+    let head = |i: usize| &data[min(n, i + 2)..min(n, i + 2 + SORT_DEPTH)];
+
+    {
+        let mut buckets = Vec::new();
+        let mut rest = &mut offsets[..];
+        for k in 0..starts.len() - 1 {
+            let (bucket, tail) = rest.split_at_mut(starts[k + 1] - starts[k]);
+            if bucket.len() > 1 {
+                buckets.push(bucket);
+            }
+            rest = tail;
+        }
+
+        // Hand out the largest buckets first, so one big bucket doesn't end up
+        // being sorted last while the other threads sit idle.
+        buckets.sort_by_key(|b| Reverse(b.len()));
+        let queue = Mutex::new(buckets.into_iter());
+
+        // Suffixes found tied so far, to give up early on repetitive data.
+        let tied = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    while tied.load(AtomicOrdering::Relaxed) <= max_unsorted {
+                        let bucket = match queue.lock().unwrap().next() {
+                            Some(bucket) => bucket,
+                            None => break,
+                        };
+
+                        bucket.sort_unstable_by(|a, b| head(a.index()).cmp(head(b.index())));
+
+                        let count = (1..bucket.len())
+                            .filter(|&p| head(bucket[p - 1].index()) == head(bucket[p].index()))
+                            .count();
+                        tied.fetch_add(count, AtomicOrdering::Relaxed);
+                    }
+                });
+            }
+        });
+
+        // This counts tied pairs, one short of the suffixes in each run, but
+        // the exact count is checked below anyway.
+        if tied.into_inner() > max_unsorted {
+            return None;
+        }
+    }
+
+    // Runs of suffixes that share their first `SORT_DEPTH + 2` bytes.  A
+    // suffix shorter than that can't tie with any other.
+    let mut groups = Vec::new();
+    let mut unsorted = 0;
+    let mut start = 0;
+    for p in 1..n + 1 {
+        let tied = p < n && {
+            let (a, b) = (offsets[p - 1].index(), offsets[p].index());
+            key(a) == key(b) && head(a) == head(b)
+        };
+
+        if !tied {
+            if p - start > 1 {
+                groups.push(start..p);
+                unsorted += p - start;
+            }
+            start = p;
+        }
+    }
+
+    if unsorted > max_unsorted {
+        return None;
+    }
+
+    if !groups.is_empty() {
+        refine(&mut offsets, data.len(), groups, SORT_DEPTH + 2, threads);
+    }
+
+    Some(offsets)
+}
+
+/// Prefix doubling: given `offsets` sorted by the first `h` bytes of each
+/// suffix, where the suffixes in each of `groups` tie, sorts them fully.
+///
+/// Each suffix's rank is the last position of its group in `offsets`, so
+/// sorting a group by the rank of the suffix `h` bytes further on sorts it by
+/// the first `2 * h` bytes.
+fn refine<O: Offset + Sync>(offsets: &mut [O], n: usize, mut groups: Vec<Range<usize>>, mut h: usize, threads: usize) {
+    let mut rank = vec![O::EMPTY; n];
+    {
+        let mut g = groups.iter().peekable();
+        for p in 0..n {
+            while g.peek().is_some_and(|r| r.end <= p) {
+                g.next();
+            }
+            let end = match g.peek() {
+                Some(r) if r.start <= p => r.end - 1,
+                _ => p,
+            };
+            rank[offsets[p].index()] = O::from_index(end);
+        }
+    }
+
+    while !groups.is_empty() {
+        // Where each group splits once sorted.  Ranks can only change once
+        // every group in this round has been sorted by the old ones.
+        let splits = groups.iter().map(|_| Mutex::new(Vec::new())).collect::<Vec<_>>();
+
+        {
+            let rank = &rank;
+            let sort_key = |i: usize| if i + h < n { rank[i + h].index() + 1 } else { 0 };
+
+            let mut slices = Vec::with_capacity(groups.len());
+            let mut rest = &mut offsets[..];
+            let mut pos = 0;
+            for (k, g) in groups.iter().enumerate() {
+                let (_, tail) = rest.split_at_mut(g.start - pos);
+                let (group, tail) = tail.split_at_mut(g.len());
+                slices.push((k, g.start, group));
+                rest = tail;
+                pos = g.end;
+            }
+
+            let queue = Mutex::new(slices.into_iter());
+
+            thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(|| {
+                        loop {
+                            let (k, start, group) = match queue.lock().unwrap().next() {
+                                Some(next) => next,
+                                None => break,
+                            };
+
+                            group.sort_unstable_by_key(|o| sort_key(o.index()));
+
+                            let mut split = vec![start];
+                            for p in 1..group.len() {
+                                if sort_key(group[p - 1].index()) != sort_key(group[p].index()) {
+                                    split.push(start + p);
+                                }
+                            }
+                            *splits[k].lock().unwrap() = split;
+                        }
+                    });
+                }
+            });
+        }
+
+        let mut next = Vec::new();
+        for (g, split) in groups.iter().zip(splits) {
+            let split = split.into_inner().unwrap();
+            for (k, &start) in split.iter().enumerate() {
+                let end = split.get(k + 1).cloned().unwrap_or(g.end);
+                for p in start..end {
+                    rank[offsets[p].index()] = O::from_index(end - 1);
+                }
+                if end - start > 1 {
+                    next.push(start..end);
+                }
+            }
+        }
+
+        groups = next;
+        h *= 2;
+    }
+}
+
+/// Computes the suffix array of `data`, given the suffix array `parent` of its
+/// first `parent_len` bytes.
+///
+/// Appending bytes only reorders suffixes of the parent that are a prefix of
+/// some other suffix of the parent - and those always form a contiguous tail
+/// of it.  Everything before that tail keeps its relative order, so only the
+/// suffixes starting in the tail (or the appended bytes) need sorting, and are
+/// then merged in.
+pub fn extend(data: &[u8], parent: &Offsets, parent_len: usize) -> Offsets {
+    if Offsets::needs_wide(data.len()) {
+        Offsets::Wide(extend_with(data, parent, parent_len))
+    } else {
+        Offsets::Narrow(extend_with(data, parent, parent_len))
+    }
+}
+
+fn extend_with<O: Offset>(data: &[u8], parent: &Offsets, parent_len: usize) -> Vec<O> {
+    let old = &data[..parent_len];
+
+    // Whether `old[x..]` is a proper prefix of another suffix of `old`.  If it
+    // is, the same is true of `old[x + 1..]`, so this is monotonic in `x`.
+    let repeats = |x: usize| {
+        if x == parent_len {
+            return true;
+        }
+
+        let suffix = &old[x..];
+        let rank = parent.binary_search_by(|v| old[v..].cmp(suffix))
+            .expect("parent index doesn't match data");
+        rank + 1 < parent.len() && old[parent.get(rank + 1)..].starts_with(suffix)
+    };
+
+    let (mut lo, mut hi) = (0, parent_len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if repeats(mid) {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    let tail_start = lo;
+
+    let tail = sa_is::<u8, O>(&data[tail_start..], 255);
+
+    let kept = (0..parent.len())
+        .map(|rank| parent.get(rank))
+        .filter(|&v| v < tail_start)
+        .collect::<Vec<_>>();
+
+    let mut offsets = Vec::with_capacity(data.len());
+    let mut next = 0;
+    for t in tail {
+        let t = t.index() + tail_start;
+        let before = |i: usize| data[kept[i]..] < data[t..];
+
+        // Find the first kept suffix that sorts after `t`, by galloping ahead
+        // from the last insertion point, then binary searching.
+        let mut lo = next;
+        let mut step = 1;
+        while lo + step <= kept.len() && before(lo + step - 1) {
+            lo += step;
+            step *= 2;
+        }
+        let mut hi = min(lo + step, kept.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if before(mid) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        offsets.extend(kept[next..lo].iter().map(|&v| O::from_index(v)));
+        offsets.push(O::from_index(t));
+        next = lo;
+    }
+    offsets.extend(kept[next..].iter().map(|&v| O::from_index(v)));
+
+    offsets
+}
+
+/// Longest-common-prefix lengths between the suffixes a binary search over a
+/// suffix array compares against each other.
+///
+/// The search always probes `mid = (lo + hi) / 2`, starting from
+/// `lo = 0, hi = len - 1`, so every `mid` is reached from exactly one
+/// `(lo, hi)` pair.  `left[mid]` is the LCP of the suffixes at `lo` and `mid`,
+/// `right[mid]` that of `mid` and `hi`.  Lengths saturate at `u32::MAX`.
+pub struct LcpTable {
+    pub left: Vec<u32>,
+    pub right: Vec<u32>,
+
+    /// `adjacent[i]` is the LCP of the suffixes at ranks `i - 1` and `i`.
+    pub adjacent: Vec<u32>,
+}
+
+impl LcpTable {
+    pub fn build(data: &[u8], offsets: &Offsets) -> LcpTable {
+        let lcp = kasai(data, offsets);
+
+        let n = lcp.len();
+        let mut table = LcpTable {
+            left: vec![0; n],
+            right: vec![0; n],
+            adjacent: Vec::new(),
+        };
+
+        if n > 1 {
+            table.fill(&lcp, 0, n - 1);
+        }
+
+        table.adjacent = lcp;
+        table
+    }
+
+    /// Fills in the entries for every probe between `lo` and `hi`, returning
+    /// the LCP of the suffixes at `lo` and `hi`.
+    fn fill(&mut self, lcp: &[u32], lo: usize, hi: usize) -> u32 {
+        if hi - lo <= 1 {
+            return lcp[hi];
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        self.left[mid] = self.fill(lcp, lo, mid);
+        self.right[mid] = self.fill(lcp, mid, hi);
+
+        min(self.left[mid], self.right[mid])
+    }
+}
+
+/// Kasai et al.'s linear-time LCP construction: `lcp[i]` is the length of the
+/// common prefix of the suffixes at ranks `i - 1` and `i` (`lcp[0]` is zero).
+fn kasai(data: &[u8], offsets: &Offsets) -> Vec<u32> {
+    let n = offsets.len();
+
+    let mut rank = vec![0usize; n];
+    for i in 0..n {
+        rank[offsets.get(i)] = i;
+    }
+
+    let mut lcp = vec![0u32; n];
+    let mut h = 0;
+    for i in 0..n {
+        if rank[i] > 0 {
+            let j = offsets.get(rank[i] - 1);
+            while i + h < n && j + h < n && data[i + h] == data[j + h] {
+                h += 1;
+            }
+            lcp[rank[i]] = min(h, u32::MAX as usize) as u32;
+            h = h.saturating_sub(1);
+        } else {
+            h = 0;
+        }
+    }
+
+    lcp
+}
+
+/// SA-IS (Nong, Zhang & Chan), over an alphabet of `0..upper + 1`.
+///
+/// The top-level call works on the raw bytes; recursive calls work on the
+/// names assigned to the sorted LMS substrings.
+fn sa_is<T: Symbol, O: Offset>(s: &[T], upper: usize) -> Vec<O> {
+    let n = s.len();
+
+    match n {
+        0 => return Vec::new(),
+        1 => return vec![O::from_index(0)],
+        2 => return if s[0].index() < s[1].index() {
+            vec![O::from_index(0), O::from_index(1)]
+        } else {
+            vec![O::from_index(1), O::from_index(0)]
+        },
+        _ => {}
+    }
+
+    // `is_s[i]` is true for S-type positions.  The last position is always
+    // L-type, since the (virtual) sentinel after it is smaller than anything.
+    let mut is_s = vec![false; n];
+    for i in (0..n - 1).rev() {
+        let (a, b) = (s[i].index(), s[i + 1].index());
+        is_s[i] = if a == b { is_s[i + 1] } else { a < b };
+    }
+
+    // Bucket boundaries: `sum_l[c]` is the start of the L-type region of
+    // bucket `c`, `sum_s[c]` the start of its S-type region.
+    let mut sum_l = vec![0usize; upper + 2];
+    let mut sum_s = vec![0usize; upper + 2];
+    for i in 0..n {
+        let c = s[i].index();
+        if is_s[i] {
+            sum_l[c + 1] += 1;
+        } else {
+            sum_s[c] += 1;
+        }
+    }
+    for c in 0..upper + 1 {
+        sum_s[c] += sum_l[c];
+        sum_l[c + 1] += sum_s[c];
+    }
+
+    let is_lms = |i: usize| i > 0 && !is_s[i - 1] && is_s[i];
+
+    let mut lms_map = vec![O::EMPTY; n];
+    let mut lms = Vec::new();
+    for (i, slot) in lms_map.iter_mut().enumerate().skip(1) {
+        if is_lms(i) {
+            *slot = O::from_index(lms.len());
+            lms.push(O::from_index(i));
+        }
+    }
+
+    let mut sa = vec![O::EMPTY; n];
+
+    induce(s, &is_s, &sum_l, &sum_s, &lms, &mut sa);
+
+    if !lms.is_empty() {
+        let m = lms.len();
+
+        let sorted_lms = sa.iter()
+            .cloned()
+            .filter(|&v| lms_map[v.index()] != O::EMPTY)
+            .collect::<Vec<_>>();
+
+        // Name each LMS substring by its rank among the distinct substrings,
+        // then recursively sort the reduced string of names.
+        let next_lms = |i: usize| {
+            let k = lms_map[i].index() + 1;
+            if k < m { lms[k].index() } else { n }
+        };
+
+        let mut names = vec![O::from_index(0); m];
+        let mut name = 0;
+        for i in 1..m {
+            let (mut l, mut r) = (sorted_lms[i - 1].index(), sorted_lms[i].index());
+            let end_l = next_lms(l);
+            let end_r = next_lms(r);
+
+            let mut same = end_l - l == end_r - r;
+            if same {
+                while l < end_l && s[l].index() == s[r].index() {
+                    l += 1;
+                    r += 1;
+                }
+                if l == n || s[l].index() != s[r].index() {
+                    same = false;
+                }
+            }
+
+            if !same {
+                name += 1;
+            }
+            names[lms_map[sorted_lms[i].index()].index()] = O::from_index(name);
+        }
+
+        let reduced_sa: Vec<O> = sa_is(&names, name);
+
+        let sorted_lms = reduced_sa.iter().map(|i| lms[i.index()]).collect::<Vec<_>>();
+
+        induce(s, &is_s, &sum_l, &sum_s, &sorted_lms, &mut sa);
+    }
+
+    sa
+}
+
+fn induce<T: Symbol, O: Offset>(
+    s: &[T],
+    is_s: &[bool],
+    sum_l: &[usize],
+    sum_s: &[usize],
+    lms: &[O],
+    sa: &mut [O])
+{
+    let n = s.len();
+
+    for v in sa.iter_mut() {
+        *v = O::EMPTY;
+    }
+
+    let mut buf = sum_s.to_vec();
+    for &d in lms {
+        let c = s[d.index()].index();
+        sa[buf[c]] = d;
+        buf[c] += 1;
+    }
+
+    buf.copy_from_slice(sum_l);
+    let c = s[n - 1].index();
+    sa[buf[c]] = O::from_index(n - 1);
+    buf[c] += 1;
+    for i in 0..n {
+        let v = sa[i];
+        if v != O::EMPTY && v.index() >= 1 && !is_s[v.index() - 1] {
+            let v = v.index() - 1;
+            let c = s[v].index();
+            sa[buf[c]] = O::from_index(v);
+            buf[c] += 1;
+        }
+    }
+
+    buf.copy_from_slice(sum_l);
+    for i in (0..n).rev() {
+        let v = sa[i];
+        if v != O::EMPTY && v.index() >= 1 && is_s[v.index() - 1] {
+            let v = v.index() - 1;
+            let c = s[v].index() + 1;
+            buf[c] -= 1;
+            sa[buf[c]] = O::from_index(v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{pseudo_random, Lcg};
+
+    fn to_vec(offsets: &Offsets) -> Vec<usize> {
+        (0..offsets.len()).map(|i| offsets.get(i)).collect()
+    }
+
+    fn naive(data: &[u8]) -> Vec<usize> {
+        let mut offsets = (0..data.len()).collect::<Vec<_>>();
+        offsets.sort_by(|&a, &b| data[a..].cmp(&data[b..]));
+        offsets
+    }
+
+    fn test_inputs() -> Vec<Vec<u8>> {
+        let mut bufs = vec![
+            b"".to_vec(),
+            b"a".to_vec(),
+            b"ab".to_vec(),
+            b"ba".to_vec(),
+            b"aaaa".to_vec(),
+            b"banana".to_vec(),
+            b"mississippi".to_vec(),
+            b"this is a test 12345678 test".to_vec(),
+            vec![0u8; 1000],
+            vec![255u8; 17],
+        ];
+
+        for &alphabet in &[2, 3, 4, 256] {
+            for seed in 0..20 {
+                bufs.push(Lcg(seed).take(1 + seed as usize * 37).map(|x| ((x >> 16) % alphabet) as u8).collect());
+            }
+        }
+
+        bufs
+    }
+
+    #[test]
+    fn test_matches_naive_sort() {
+        for buf in &test_inputs() {
+            assert_eq!(naive(buf), to_vec(&build(buf)), "data: {:?}", buf);
+        }
+    }
+
+    #[test]
+    fn test_wide_matches_narrow() {
+        for buf in &test_inputs() {
+            let narrow = sa_is::<u8, u32>(buf, 255).into_iter().map(|o| o as usize).collect::<Vec<_>>();
+            let wide = sa_is::<u8, u64>(buf, 255).into_iter().map(|o| o as usize).collect::<Vec<_>>();
+            assert_eq!(narrow, wide, "data: {:?}", buf);
+        }
+    }
+
+    #[test]
+    fn test_kasai_matches_naive_lcp() {
+        for buf in &test_inputs() {
+            let offsets = build(buf);
+            let lcp = kasai(buf, &offsets);
+
+            for i in 1..offsets.len() {
+                let (a, b) = (&buf[offsets.get(i - 1)..], &buf[offsets.get(i)..]);
+                let expected = a.iter().zip(b).take_while(|&(x, y)| x == y).count();
+                assert_eq!(expected, lcp[i] as usize, "data: {:?}", buf);
+            }
+        }
+    }
+
+    #[test]
+    fn test_extend_matches_build() {
+        for buf in &test_inputs() {
+            let expected = to_vec(&build(buf));
+            for &parent_len in &[0, 1, buf.len() / 3, buf.len() / 2, buf.len()] {
+                let parent_len = min(parent_len, buf.len());
+                let parent = build(&buf[..parent_len]);
+                assert_eq!(expected, to_vec(&extend(buf, &parent, parent_len)),
+                    "data: {:?} parent_len: {}", buf, parent_len);
+            }
+        }
+    }
+
+    /// Inputs that leave many suffixes tied after the first bucket sort.
+    fn repetitive_inputs() -> Vec<Vec<u8>> {
+        let block = pseudo_random(300, 1);
+
+        let mut repeated = pseudo_random(500, 2);
+        repeated.extend_from_slice(&block);
+        repeated.extend_from_slice(&pseudo_random(200, 3));
+        repeated.extend_from_slice(&block);
+        repeated.extend_from_slice(&block[..150]);
+
+        vec![
+            vec![0u8; 5000],
+            b"ab".iter().cycle().take(3001).cloned().collect(),
+            b"abcdefg".iter().cycle().take(4000).cloned().collect(),
+            block.iter().cycle().take(4000).cloned().collect(),
+            repeated,
+        ]
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        for buf in test_inputs().iter().chain(&repetitive_inputs()) {
+            let expected = to_vec(&build(buf));
+            for &threads in &[1, 2, 3, 8] {
+                assert_eq!(expected, to_vec(&build_parallel(buf, threads)), "data: {:?}", buf);
+            }
+        }
+    }
+
+    #[test]
+    fn test_refinement_matches_naive_sort() {
+        // Never fall back, so that ties are always refined.
+        for buf in test_inputs().iter().chain(&repetitive_inputs()) {
+            let expected = naive(buf);
+            for &threads in &[1, 3] {
+                let offsets = bucket_sort::<u32>(buf, threads, buf.len()).unwrap();
+                assert_eq!(expected, offsets.into_iter().map(|o| o as usize).collect::<Vec<_>>(), "data: {:?}", buf);
+            }
+        }
+    }
+
+    #[test]
+    fn test_repetitive_data_falls_back() {
+        for buf in &repetitive_inputs()[..4] {
+            assert!(bucket_sort::<u32>(buf, 4, buf.len() / 4).is_none());
+        }
+    }
+}
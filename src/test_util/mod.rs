@@ -0,0 +1,34 @@
+//! Helpers shared by the unit tests.
+
+use std::io::Cursor;
+
+use diff::{DiffOptions, MatchStrategy};
+use format::{bsdiff, linear_diff};
+
+pub use self::random::{Lcg, pseudo_random};
+
+mod random;
+
+/// A small old file, and a new one with a few edits, that most matchers are
+/// tried on.
+pub const OLD: &'static [u8] = b"this is a test 12345678 test, with a little more text on the end";
+pub const NEW: &'static [u8] = b"this is really a cool uftu 12345678 uftu, with a little less text on the end";
+
+/// Diffs `new` against the strategy's old data in both patch formats, checks
+/// that each patch applies back to `new`, and returns the bsdiff patch.
+pub fn assert_roundtrip<S: MatchStrategy>(strategy: &S, new: &[u8], options: &DiffOptions) -> Vec<u8> {
+    let old = strategy.old();
+
+    let patch = bsdiff::generate_full_patch(strategy, new, options);
+    let mut result = Vec::new();
+    bsdiff::apply_patch(&patch, Cursor::new(old), &mut result).unwrap();
+    assert!(new == &result[..], "bsdiff patch doesn't roundtrip");
+
+    let mut linear = Vec::new();
+    linear_diff::generate_full_patch(strategy, new, options, &mut linear).unwrap();
+    let mut result = Vec::new();
+    linear_diff::apply_patch(Cursor::new(&linear), Cursor::new(old), &mut result).unwrap();
+    assert!(new == &result[..], "linear_diff patch doesn't roundtrip");
+
+    patch
+}
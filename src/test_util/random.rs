@@ -0,0 +1,21 @@
+//! A small deterministic generator for test and benchmark inputs.  Kept free
+//! of crate dependencies so benchmarks can include it with `#[path]`.
+
+/// The linear congruential generator from the C standard's example `rand`.
+/// Yields the whole state after each step; the high bits are the random ones.
+pub struct Lcg(pub u32);
+
+impl Iterator for Lcg {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+        Some(self.0)
+    }
+}
+
+/// `len` pseudo-random bytes, the same for the same `seed`.
+#[allow(dead_code)]
+pub fn pseudo_random(len: usize, seed: u32) -> Vec<u8> {
+    Lcg(seed).take(len).map(|x| (x >> 16) as u8).collect()
+}